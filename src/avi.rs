@@ -0,0 +1,357 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::ColorType;
+use crate::capturer::{self, Frame, PixelFormat};
+use crate::recorder::FrameWriter;
+
+// the headers written by `header`, up to the first chunk of the movi list
+const HEADER_LEN: u32 = 224;
+// how often the file is made whole, bounding what a killed process loses
+const CHECKPOINT: Duration = Duration::from_secs(1);
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// Writes an MJPEG video in an AVI file, as the frames arrive. Every frame has the size of the
+/// first one, others are scaled to it. The file is made whole, index included, every second and
+/// when the writer is dropped, so that it plays even if the session ends abruptly.
+///
+/// AVI plays at a constant rate, so frames are placed by their timestamp: gaps are filled with
+/// empty chunks, which players take as repeats of the previous frame, and frames arriving faster
+/// than the rate are dropped. The video lasts as long as the session did.
+pub struct AviWriter {
+    file: BufWriter<File>,
+    fps: u32,
+    quality: u8,
+    size: Option<(u32, u32)>, // of the first frame
+    index: Vec<(u32, u32)>,   // offset from the movi list and length of each frame, 0 for a repeat
+    movi_len: u32,            // bytes of the chunks in the movi list
+    jpeg: Vec<u8>,
+    last_checkpoint: Instant,
+    finished: bool,
+}
+
+impl AviWriter {
+    pub fn create(path: &Path, fps: u32, quality: u8) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            fps: fps.max(1),
+            quality,
+            size: None,
+            index: Vec::new(),
+            movi_len: 0,
+            jpeg: Vec::new(),
+            last_checkpoint: Instant::now(),
+            finished: false,
+        };
+        writer.file.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        writer.checkpoint()?;
+        Ok(writer)
+    }
+    // the frame of the video showing at `timestamp`, in ms from its start
+    fn slot(&self, timestamp: u64) -> u64 {
+        (timestamp * self.fps as u64 + 500) / 1000
+    }
+    /// Writes `frame`, captured `timestamp` ms after the start of the video.
+    pub fn write(&mut self, timestamp: u64, frame: &Frame) -> io::Result<()> {
+        let repeats = match self.slot(timestamp).checked_sub(self.index.len() as u64) {
+            Some(repeats) => repeats,
+            None => return Ok(()),
+        };
+        let frame = frame.to_format(PixelFormat::Rgb);
+        let (w, h) = *self.size.get_or_insert((frame.w, frame.h));
+        let frame = if (frame.w, frame.h) != (w, h) { Cow::Owned(capturer::resize(&frame, w, h, FilterType::Triangle)) } else { frame };
+        self.jpeg.clear();
+        JpegEncoder::new_with_quality(&mut self.jpeg, self.quality)
+            .encode(&frame.data, frame.w, frame.h, ColorType::Rgb8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // AVI 1.0 counts in u32: the file, index included, must stay under 4 GiB
+        let len = self.jpeg.len() as u64;
+        let chunks = repeats + 1;
+        let total = HEADER_LEN as u64 + self.movi_len as u64 + 8 * chunks + len + len % 2 + 8 + 16 * (self.index.len() as u64 + chunks);
+        if total > u32::MAX as u64 {
+            return Err(io::Error::other("the AVI file reached its maximum size of 4 GiB"));
+        }
+        for _ in 0..repeats {
+            self.chunk(&[])?;
+        }
+        let jpeg = mem::take(&mut self.jpeg);
+        let written = self.chunk(&jpeg);
+        self.jpeg = jpeg;
+        written?;
+        if self.last_checkpoint.elapsed() >= CHECKPOINT {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+    fn chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = data.len() as u32;
+        self.file.write_all(b"00dc")?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(data)?;
+        if len % 2 == 1 {
+            self.file.write_all(&[0])?;
+        }
+        self.index.push((4 + self.movi_len, len));
+        self.movi_len += 8 + len + len % 2;
+        Ok(())
+    }
+    /// Completes the video, which ends `timestamp` ms after its start: the last frame is repeated
+    /// until then.
+    pub fn finish(mut self, timestamp: u64) -> io::Result<()> {
+        self.finished = true;
+        if !self.index.is_empty() {
+            for _ in self.index.len() as u64..self.slot(timestamp) {
+                self.chunk(&[])?;
+            }
+        }
+        self.checkpoint()
+    }
+    // writes the index after the frames so far and the headers that count them, then goes back to
+    // the end of the frames, where the next one overwrites the index
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.file.write_all(b"idx1")?;
+        self.file.write_all(&(16 * self.index.len() as u32).to_le_bytes())?;
+        for &(offset, len) in &self.index {
+            let flags = if len > 0 { AVIIF_KEYFRAME } else { 0 };
+            for value in [u32::from_le_bytes(*b"00dc"), flags, offset, len] {
+                self.file.write_all(&value.to_le_bytes())?;
+            }
+        }
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.header())?;
+        self.file.seek(SeekFrom::Start((HEADER_LEN + self.movi_len) as u64))?;
+        self.file.flush()?;
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+    // RIFF header, stream headers and the start of the movi list
+    fn header(&self) -> Vec<u8> {
+        let (w, h) = self.size.unwrap_or((0, 0));
+        let frames = self.index.len() as u32;
+        let max_chunk = self.index.iter().map(|(_, len)| len + len % 2).max().unwrap_or(0);
+        let riff_len = HEADER_LEN - 8 + self.movi_len + 8 + 16 * frames;
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        let mut put = |values: &[u32]| values.iter().for_each(|v| header.extend_from_slice(&v.to_le_bytes()));
+        let fourcc = |code: &[u8; 4]| u32::from_le_bytes(*code);
+        put(&[fourcc(b"RIFF"), riff_len, fourcc(b"AVI ")]);
+        put(&[fourcc(b"LIST"), 192, fourcc(b"hdrl")]);
+        // main header: µs per frame, max bytes per second, padding, flags, frames, initial frames,
+        // streams, suggested buffer size, size, reserved
+        put(&[fourcc(b"avih"), 56, 1_000_000 / self.fps, max_chunk * self.fps, 0, AVIF_HASINDEX, frames, 0, 1, max_chunk, w, h, 0, 0, 0, 0]);
+        put(&[fourcc(b"LIST"), 116, fourcc(b"strl")]);
+        // stream header: type, handler, flags, priority and language, initial frames, scale, rate,
+        // start, length, suggested buffer size, quality, sample size, frame rectangle
+        put(&[fourcc(b"strh"), 56, fourcc(b"vids"), fourcc(b"MJPG"), 0, 0, 0, 1, self.fps, 0, frames, max_chunk, u32::MAX, 0, 0, w & 0xFFFF | h << 16]);
+        // stream format, a BITMAPINFOHEADER
+        put(&[fourcc(b"strf"), 40, 40, w, h, 1 | 24 << 16, fourcc(b"MJPG"), w * h * 3, 0, 0, 0, 0]);
+        put(&[fourcc(b"LIST"), 4 + self.movi_len, fourcc(b"movi")]);
+        debug_assert_eq!(header.len(), HEADER_LEN as usize);
+        header
+    }
+}
+
+impl Drop for AviWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.checkpoint();
+        }
+    }
+}
+
+impl FrameWriter for AviWriter {
+    fn write(&mut self, timestamp: u64, frame: &Frame) -> io::Result<()> {
+        AviWriter::write(self, timestamp, frame)
+    }
+    fn finish(self, timestamp: u64) -> io::Result<()> {
+        AviWriter::finish(self, timestamp)
+    }
+}
+
+/// Reads back the frames of an MJPEG AVI file, through its index. Empty chunks, which repeat the
+/// previous frame, are skipped but still count for the timing.
+pub struct AviReader {
+    file: BufReader<File>,
+    fps: u32,
+    index: Vec<(u64, u64, u32)>, // number, position in the file and length of each frame
+}
+
+impl AviReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display()));
+        let mut file = BufReader::new(File::open(path)?);
+        let mut riff = [0; 12];
+        file.read_exact(&mut riff)?;
+        if &riff[..4] != b"RIFF" || &riff[8..] != b"AVI " {
+            return Err(invalid("not an AVI file"));
+        }
+        let (mut fps, mut movi, mut index) = (None, None, Vec::new());
+        // chunks and lists of the top level, the stream headers are looked for in hdrl
+        let mut chunk = [0; 8];
+        while file.read_exact(&mut chunk).is_ok() {
+            let len = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+            let start = file.stream_position()?;
+            let mut kind = [0; 4];
+            match &chunk[..4] {
+                b"LIST" => {
+                    file.read_exact(&mut kind)?;
+                    if &kind == b"hdrl" {
+                        let mut hdrl = vec![0; (len as usize).saturating_sub(4)];
+                        file.read_exact(&mut hdrl)?;
+                        // the scale and rate of the stream header, which follow its type and handler
+                        if let Some(i) = hdrl.windows(4).position(|w| w == b"strh") {
+                            let field = |at: usize| hdrl.get(i + 8 + at..i + 12 + at).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+                            if let (Some(scale), Some(rate)) = (field(20), field(24)) {
+                                fps = Some((rate / scale.max(1)).max(1));
+                            }
+                        }
+                    } else if &kind == b"movi" {
+                        movi = Some(start);
+                    }
+                }
+                b"idx1" => {
+                    let movi = movi.ok_or_else(|| invalid("index before the frames"))?;
+                    let mut entries = vec![0; len as usize];
+                    file.read_exact(&mut entries)?;
+                    let video = entries.chunks_exact(16).filter(|entry| &entry[2..4] == b"dc");
+                    for (number, entry) in video.enumerate() {
+                        let value = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+                        if value(12) > 0 {
+                            // the offset is from the movi list, and points at the chunk header
+                            index.push((number as u64, movi + value(8) as u64 + 8, value(12)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            file.seek(SeekFrom::Start(start + (len + len % 2) as u64))?;
+        }
+        let fps = fps.ok_or_else(|| invalid("no video stream"))?;
+        // the index of a video still being written is overwritten by the frames that followed the
+        // last checkpoint: they are then found by walking the frames from the movi list on
+        if index.is_empty() {
+            if let Some(movi) = movi {
+                index = scan(&mut file, movi)?;
+            }
+        }
+        Ok(Self { file, fps, index })
+    }
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+    /// When the i-th frame shows, in ms from the start of the video.
+    pub fn timestamp(&self, i: usize) -> u64 {
+        self.index[i].0 * 1000 / self.fps as u64
+    }
+    /// Returns the JPEG data of the i-th frame.
+    pub fn read(&mut self, i: usize) -> io::Result<Vec<u8>> {
+        let (_, position, len) = self.index[i];
+        let mut jpeg = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(position))?;
+        self.file.read_exact(&mut jpeg)?;
+        Ok(jpeg)
+    }
+}
+
+// the frames from the movi list at `movi` to the last whole one of the file
+fn scan(file: &mut BufReader<File>, movi: u64) -> io::Result<Vec<(u64, u64, u32)>> {
+    let end = file.get_ref().metadata()?.len();
+    let mut index = Vec::new();
+    let mut position = movi + 4;
+    let mut chunk = [0; 8];
+    for number in 0.. {
+        file.seek(SeekFrom::Start(position))?;
+        if file.read_exact(&mut chunk).is_err() || &chunk[2..4] != b"dc" {
+            break;
+        }
+        let len = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+        if position + 8 + len as u64 > end {
+            break;
+        }
+        if len > 0 {
+            index.push((number, position + 8, len));
+        }
+        position += 8 + (len + len % 2) as u64;
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(w: u32, h: u32, value: u8) -> Frame {
+        Frame::new(w, h, PixelFormat::Rgb, vec![value; (w * h * 3) as usize])
+    }
+
+    #[test]
+    fn avi_is_readable_back() {
+        let path = std::env::temp_dir().join(format!("avi_test_{}.avi", std::process::id()));
+        let mut writer = AviWriter::create(&path, 25, 90).unwrap();
+        writer.write(0, &frame(64, 48, 0)).unwrap();
+        writer.write(40, &frame(64, 48, 255)).unwrap();
+        // a frame of another size is scaled to the first one
+        writer.write(80, &frame(32, 24, 128)).unwrap();
+        writer.finish(120).unwrap();
+
+        let mut reader = AviReader::open(&path).unwrap();
+        assert_eq!((reader.len(), reader.timestamp(2)), (3, 80));
+        let last = image::load_from_memory(&reader.read(2).unwrap()).unwrap().into_rgb8();
+        assert_eq!(last.dimensions(), (64, 48));
+        assert!(last.pixels().all(|p| p.0.iter().all(|c| c.abs_diff(128) < 4)));
+        assert_eq!(std::fs::metadata(&path).unwrap().len() as u32, u32::from_le_bytes(std::fs::read(&path).unwrap()[4..8].try_into().unwrap()) + 8);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dropped_writer_leaves_a_whole_file() {
+        let path = std::env::temp_dir().join(format!("avi_drop_test_{}.avi", std::process::id()));
+        {
+            let mut writer = AviWriter::create(&path, 10, 80).unwrap();
+            writer.write(0, &frame(16, 16, 10)).unwrap();
+        }
+        assert_eq!(AviReader::open(&path).unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn video_being_written_is_readable() {
+        let path = std::env::temp_dir().join(format!("avi_live_test_{}.avi", std::process::id()));
+        let mut writer = AviWriter::create(&path, 10, 80).unwrap();
+        for (timestamp, value) in [(0, 0), (100, 50), (300, 100)] {
+            writer.write(timestamp, &frame(16, 16, value)).unwrap();
+        }
+        // the frames are on disk, but no checkpoint indexed them yet
+        writer.file.flush().unwrap();
+        let reader = AviReader::open(&path).unwrap();
+        let timestamps: Vec<u64> = (0..reader.len()).map(|i| reader.timestamp(i)).collect();
+        assert_eq!(timestamps, [0, 100, 300]);
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn frames_are_placed_by_their_timestamp() {
+        let path = std::env::temp_dir().join(format!("avi_timing_test_{}.avi", std::process::id()));
+        let mut writer = AviWriter::create(&path, 10, 80).unwrap();
+        // at 10 fps, the second frame comes too early and the third one after a gap of two
+        for (timestamp, value) in [(0, 0), (30, 50), (310, 100), (420, 200)] {
+            writer.write(timestamp, &frame(16, 16, value)).unwrap();
+        }
+        // the last frame lasts until the end of the session
+        writer.finish(1000).unwrap();
+
+        let reader = AviReader::open(&path).unwrap();
+        let timestamps: Vec<u64> = (0..reader.len()).map(|i| reader.timestamp(i)).collect();
+        assert_eq!(timestamps, [0, 300, 400]);
+        assert_eq!(u32::from_le_bytes(std::fs::read(&path).unwrap()[48..52].try_into().unwrap()), 10);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+pub const MAX_FPS: u32 = 30;
+const MIN_FPS: u32 = 5;
+const MIN_SCALE: f32 = 0.25;
+const SCALE_STEP: f32 = 0.75;
+const EVALUATION_PERIOD: Duration = Duration::from_secs(1);
+// share of the time spent blocked on the socket beyond which the link is considered congested
+const MAX_BACKPRESSURE: f32 = 0.5;
+
+/// The quality the sender is currently streaming at, and the measures that led to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OperatingPoint {
+    pub fps: u32,
+    pub scale: f32,
+    pub bitrate: u64,
+    pub backpressure: f32,
+}
+
+/// Keeps the sender under a bitrate cap.
+/// The sender reports every write; once per evaluation period the measured throughput and the
+/// time spent blocked on the socket decide whether to lower the quality (fps first, then scale)
+/// or to raise it back (scale first, then fps) when there is enough headroom.
+pub struct RateController {
+    cap: u64, // bit/s, 0 means no cap
+    fps: u32,
+    scale: f32,
+    scaling: bool, // false when the output size is fixed, so that only the fps is lowered
+    window_start: Instant,
+    bytes: u64,
+    blocked: Duration,
+}
+
+impl RateController {
+    pub fn new(cap: u64) -> Self {
+        Self { cap, fps: MAX_FPS, scale: 1.0, scaling: true, window_start: Instant::now(), bytes: 0, blocked: Duration::ZERO }
+    }
+    pub fn set_scaling(&mut self, scaling: bool) {
+        self.scaling = scaling;
+        if !scaling {
+            self.scale = 1.0;
+        }
+    }
+    pub fn set_cap(&mut self, cap: u64) {
+        self.cap = cap;
+    }
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.fps
+    }
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+    /// Records a write of `bytes` that kept the sender blocked for `blocked`.
+    /// Returns the new operating point at the end of each evaluation period.
+    pub fn record(&mut self, bytes: usize, blocked: Duration) -> Option<OperatingPoint> {
+        self.record_at(bytes, blocked, Instant::now())
+    }
+    // `record` for a write that ended at `now`
+    fn record_at(&mut self, bytes: usize, blocked: Duration, now: Instant) -> Option<OperatingPoint> {
+        self.bytes += bytes as u64;
+        self.blocked += blocked;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < EVALUATION_PERIOD {
+            return None;
+        }
+
+        let bitrate = (self.bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+        let backpressure = self.blocked.as_secs_f32() / elapsed.as_secs_f32();
+        let capped = self.cap > 0 && bitrate > self.cap;
+        if capped || backpressure > MAX_BACKPRESSURE {
+            if self.fps > MIN_FPS {
+                self.fps = (self.fps * 3 / 4).max(MIN_FPS);
+            } else if self.scaling && self.scale > MIN_SCALE {
+                self.scale = (self.scale * SCALE_STEP).max(MIN_SCALE);
+            }
+        } else if backpressure < MAX_BACKPRESSURE / 2.0 {
+            // raise only if the predicted bitrate still fits under the cap
+            if self.scale < 1.0 {
+                let scale = (self.scale / SCALE_STEP).min(1.0);
+                let predicted = bitrate as f64 * (scale / self.scale).powi(2) as f64;
+                if self.cap == 0 || predicted < self.cap as f64 {
+                    self.scale = scale;
+                }
+            } else if self.fps < MAX_FPS {
+                let fps = (self.fps + (self.fps / 4).max(1)).min(MAX_FPS);
+                let predicted = bitrate as f64 * fps as f64 / self.fps as f64;
+                if self.cap == 0 || predicted < self.cap as f64 {
+                    self.fps = fps;
+                }
+            }
+        }
+
+        self.window_start = now;
+        self.bytes = 0;
+        self.blocked = Duration::ZERO;
+        Some(OperatingPoint { fps: self.fps, scale: self.scale, bitrate, backpressure })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a whole evaluation period at `bitrate`, blocked on the socket for the `backpressure` share of it
+    fn period(rate: &mut RateController, now: &mut Instant, bitrate: u64, backpressure: f32) -> OperatingPoint {
+        *now += EVALUATION_PERIOD;
+        rate.record_at((bitrate / 8) as usize, EVALUATION_PERIOD.mul_f32(backpressure), *now).unwrap()
+    }
+
+    fn controller(cap: u64) -> (RateController, Instant) {
+        let mut rate = RateController::new(cap);
+        let now = Instant::now();
+        rate.window_start = now;
+        (rate, now)
+    }
+
+    #[test]
+    fn over_the_cap_the_fps_is_lowered_then_the_scale() {
+        let (mut rate, mut now) = controller(1_000_000);
+        // nothing is decided before the end of the period
+        assert!(rate.record_at(1_000_000, Duration::ZERO, now + EVALUATION_PERIOD / 2).is_none());
+        let points: Vec<(u32, f32)> = (0..9).map(|_| period(&mut rate, &mut now, 4_000_000, 0.0)).map(|p| (p.fps, p.scale)).collect();
+        assert_eq!(points, [(22, 1.0), (16, 1.0), (12, 1.0), (9, 1.0), (6, 1.0), (5, 1.0), (5, 0.75), (5, 0.5625), (5, 0.421875)]);
+        // neither goes below its minimum
+        for _ in 0..10 {
+            period(&mut rate, &mut now, 4_000_000, 0.0);
+        }
+        assert_eq!((rate.fps, rate.scale), (MIN_FPS, MIN_SCALE));
+    }
+
+    #[test]
+    fn a_fixed_output_size_only_lowers_the_fps() {
+        let (mut rate, mut now) = controller(1_000_000);
+        rate.set_scaling(false);
+        for _ in 0..10 {
+            period(&mut rate, &mut now, 4_000_000, 0.0);
+        }
+        assert_eq!((rate.fps, rate.scale), (MIN_FPS, 1.0));
+    }
+
+    #[test]
+    fn a_congested_link_lowers_the_quality_without_a_cap() {
+        let (mut rate, mut now) = controller(0);
+        assert_eq!(period(&mut rate, &mut now, 100_000_000, 0.8).fps, 22);
+        // between the two thresholds, the quality is kept
+        assert_eq!(period(&mut rate, &mut now, 100_000_000, 0.4).fps, 22);
+    }
+
+    #[test]
+    fn with_headroom_the_scale_is_raised_back_then_the_fps() {
+        let (mut rate, mut now) = controller(10_000_000);
+        (rate.fps, rate.scale) = (MIN_FPS, 0.5625);
+        let points: Vec<(u32, f32)> = (0..4).map(|_| period(&mut rate, &mut now, 1_000_000, 0.0)).map(|p| (p.fps, p.scale)).collect();
+        assert_eq!(points, [(5, 0.75), (5, 1.0), (6, 1.0), (7, 1.0)]);
+        // not if the bitrate predicted would exceed the cap: 9 Mbit/s at 7 fps is 10.3 at 8 fps
+        assert_eq!(period(&mut rate, &mut now, 9_000_000, 0.0).fps, 7);
+        // nor while the link is busy
+        assert_eq!(period(&mut rate, &mut now, 1_000_000, 0.3).fps, 7);
+    }
+}
@@ -1,14 +1,42 @@
-use std::time::Duration;
+use std::borrow::Cow;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{fmt, io, thread};
+use image::imageops::FilterType;
 use scrap::{Capturer, Display};
+use serde::{Deserialize, Serialize};
+use crate::desktop::DesktopSource;
+use crate::pattern::TestPattern;
+use crate::replay::ReplaySource;
 
 pub const FPS_SLEEP: Duration = Duration::from_millis(1000 / 30);
-#[derive(Debug, Default)]
+// consecutive capture errors tolerated before the display is given up for lost
+const MAX_CAPTURE_ERRORS: u32 = 10;
+// smallest area that can be cast, on each side
+pub const MIN_AREA_SIZE: u32 = 16;
+// a still screen yields no new frame on some backends: past this wait the last frame is repeated
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+#[derive(Debug, Default, Clone)]
 pub struct Frame {
     pub w: u32,
     pub h: u32,
+    pub stride: u32, // bytes per row, padding included
+    pub format: PixelFormat,
     pub data: Vec<u8>,
 }
-#[derive(Debug, Default, Clone)]
+/// How the pixels of a frame are laid out. Alpha is never used: conversions to a format with
+/// alpha make every pixel opaque.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// What the screen capturer yields.
+    #[default]
+    Bgra,
+    Rgba,
+    /// What is cast, and what the sinks of the receiver get.
+    Rgb,
+}
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Area {
     pub x: u32,
     pub y: u32,
@@ -16,57 +44,462 @@ pub struct Area {
     pub height: u32,
     pub selected_display: u32,
 }
+/// Produces the frames to cast, in whatever pixel format suits the source.
+pub trait FrameSource {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    /// Blocks until the next frame is available. The frame is lent rather than returned, so that
+    /// sources can reuse its buffer instead of allocating one for every frame.
+    fn frame(&mut self) -> Result<&Frame, CaptureError>;
+    /// Moves a pre-recorded source to `position`; live sources ignore it.
+    fn seek(&mut self, _position: Duration) {}
+    /// Playback position and total duration of a pre-recorded source.
+    fn position(&self) -> Option<(Duration, Duration)> {
+        None
+    }
+}
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum SourceKind {
+    #[default]
+    Screen,
+    /// Every display, at the offsets given in the order of `Display::all` (see `desktop::arrange`).
+    Desktop { offsets: Vec<(i32, i32)> },
+    TestPattern { width: u32, height: u32 },
+    Replay { path: PathBuf, looping: bool },
+}
+/// Why a source could not be opened or stopped producing frames.
+#[derive(Debug)]
+pub enum CaptureError {
+    NoDisplays,
+    DisplayNotFound { selected: u32, available: u32 },
+    /// The display was found but the capture could not begin.
+    Start { display: u32, error: io::Error },
+    /// The display kept failing, most likely because it was unplugged.
+    DisplayLost { display: u32, error: io::Error },
+    /// No frame came in time, the display may still recover.
+    Stalled { display: u32 },
+    /// A source other than the screen could not be opened.
+    Source(io::Error),
+}
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::NoDisplays => write!(f, "no display found"),
+            CaptureError::DisplayNotFound { selected, available } => {
+                write!(f, "display {selected} not found, {available} available")
+            }
+            CaptureError::Start { display, error } => write!(f, "impossible capturing display {display}: {error}"),
+            CaptureError::DisplayLost { display, error } => write!(f, "display {display} lost: {error}"),
+            CaptureError::Stalled { display } => write!(f, "no frame from display {display} in {STALL_TIMEOUT:?}"),
+            CaptureError::Source(error) => write!(f, "{error}"),
+        }
+    }
+}
+impl Error for CaptureError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CaptureError::Start { error, .. } | CaptureError::DisplayLost { error, .. } | CaptureError::Source(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for CaptureError {
+    fn from(error: io::Error) -> Self {
+        CaptureError::Source(error)
+    }
+}
+/// Size of the frames sent, whatever the size of the area captured. Frames are never enlarged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum OutputSize {
+    /// The size of the area, lowered by the bandwidth controller when the link is congested.
+    #[default]
+    FitToBandwidth,
+    /// At most this many rows, e.g. 720 for 720p, keeping the aspect ratio.
+    Height(u32),
+    /// A fraction of the size of the area.
+    Scale(f32),
+}
+impl OutputSize {
+    pub const PRESETS: [(OutputSize, &'static str); 5] = [
+        (OutputSize::FitToBandwidth, "Fit to bandwidth"),
+        (OutputSize::Height(1080), "1080p"),
+        (OutputSize::Height(720), "720p"),
+        (OutputSize::Scale(0.5), "50%"),
+        (OutputSize::Scale(1.0), "Original"),
+    ];
+    /// Size of the frames sent for a `width`x`height` area; `adaptive_scale` is the scale chosen
+    /// by the bandwidth controller, which only applies when fitting to the bandwidth.
+    pub fn target(&self, width: u32, height: u32, adaptive_scale: f32) -> (u32, u32) {
+        let scale = match *self {
+            OutputSize::FitToBandwidth => adaptive_scale,
+            OutputSize::Height(rows) => rows as f32 / height.max(1) as f32,
+            OutputSize::Scale(scale) => scale,
+        };
+        if scale >= 1.0 {
+            return (width, height);
+        }
+        (((width as f32 * scale) as u32).max(1), ((height as f32 * scale) as u32).max(1))
+    }
+    /// Name of the preset, for the gui and the command line.
+    pub fn name(&self) -> String {
+        match OutputSize::PRESETS.iter().find(|(preset, _)| preset == self) {
+            Some((_, name)) => name.to_string(),
+            None => format!("{self:?}"),
+        }
+    }
+}
+/// Why an area cannot be cast from a display.
+#[derive(Debug, PartialEq)]
+pub enum AreaError {
+    /// The origin lies outside the display.
+    OutOfBounds { x: u32, y: u32, display_width: u32, display_height: u32 },
+    /// The area, once inside the display, is smaller than `MIN_AREA_SIZE` on some side.
+    TooSmall { width: u32, height: u32, min: u32 },
+}
+impl fmt::Display for AreaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AreaError::OutOfBounds { x, y, display_width, display_height } => {
+                write!(f, "origin ({x}, {y}) is outside the {display_width}x{display_height} display")
+            }
+            AreaError::TooSmall { width, height, min } => {
+                write!(f, "area {width}x{height} is too small, it must be at least {min}x{min}")
+            }
+        }
+    }
+}
+impl Error for AreaError {}
+/// Captures a display through scrap.
+pub struct ScreenSource {
+    cpt: Capturer,
+    display: u32,
+    frame: Frame, // the last one captured, repeated while the display yields nothing new
+}
+impl ScreenSource {
+    pub fn open(selected_display: u32) -> Result<Self, CaptureError> {
+        Ok(Self { cpt: create(selected_display)?, display: selected_display, frame: Frame::default() })
+    }
+}
+impl FrameSource for ScreenSource {
+    fn width(&self) -> u32 {
+        self.cpt.width() as u32
+    }
+    fn height(&self) -> u32 {
+        self.cpt.height() as u32
+    }
+    fn frame(&mut self) -> Result<&Frame, CaptureError> {
+        match capture(&mut self.cpt, self.display, &mut self.frame.data) {
+            Ok(()) => {}
+            Err(CaptureError::Stalled { .. }) if !self.frame.data.is_empty() => return Ok(&self.frame),
+            Err(e) => return Err(e),
+        }
+        self.frame.w = self.width();
+        self.frame.h = self.height();
+        // some backends pad the rows, so the stride comes from the buffer rather than the width
+        self.frame.stride = self.frame.data.len() as u32 / self.frame.h.max(1);
+        Ok(&self.frame)
+    }
+}
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba => 4,
+            PixelFormat::Rgb => 3,
+        }
+    }
+    /// The opaque pixel of colour `rgb` in this format; only its first `bytes_per_pixel` bytes
+    /// are meaningful.
+    pub fn encode(self, rgb: [u8; 3]) -> [u8; 4] {
+        let [r, g, b] = rgb;
+        match self {
+            PixelFormat::Bgra => [b, g, r, 255],
+            PixelFormat::Rgba => [r, g, b, 255],
+            PixelFormat::Rgb => [r, g, b, 0],
+        }
+    }
+}
 impl Frame {
-    pub fn new(w: u32, h: u32, data: Vec<u8>) -> Self {
-        Self { w, h, data }
+    /// Creates a frame without padding at the end of its rows.
+    pub fn new(w: u32, h: u32, format: PixelFormat, data: Vec<u8>) -> Self {
+        Self { w, h, stride: w * format.bytes_per_pixel(), format, data }
+    }
+    pub fn with_stride(w: u32, h: u32, stride: u32, format: PixelFormat, data: Vec<u8>) -> Self {
+        Self { w, h, stride, format, data }
+    }
+    /// Returns the y-th row without its padding.
+    pub fn row(&self, y: u32) -> &[u8] {
+        let start = (y * self.stride) as usize;
+        &self.data[start..start + (self.w * self.format.bytes_per_pixel()) as usize]
+    }
+    /// Tells whether `data` holds every row.
+    pub fn is_consistent(&self) -> bool {
+        let bpp = self.format.bytes_per_pixel();
+        self.h == 0 || (self.stride >= self.w * bpp && self.data.len() >= ((self.h - 1) * self.stride + self.w * bpp) as usize)
+    }
+    /// Tells whether the rows follow each other without padding.
+    pub fn is_packed(&self) -> bool {
+        self.stride == self.w * self.format.bytes_per_pixel()
+    }
+    /// Borrows the frame if it is packed `format` already, or else converts it.
+    pub fn to_format(&self, format: PixelFormat) -> Cow<'_, Frame> {
+        if self.format == format && self.is_packed() {
+            Cow::Borrowed(self)
+        } else {
+            Cow::Owned(convert(self, format, Vec::new()))
+        }
+    }
+    /// Returns the frame in `format`, converting it only if it is in another one.
+    pub fn into_format(self, format: PixelFormat) -> Frame {
+        if self.format == format {
+            self
+        } else {
+            convert(&self, format, Vec::new())
+        }
     }
 }
 impl Area {
     pub fn new(x: u32, y: u32, width: u32, height: u32, selected_display: u32) -> Self {
         Self { x, y, width, height, selected_display }
     }
+    /// Trims the area so that it fits a `display_width`x`display_height` display.
+    /// Fails if the origin is outside the display or if what is left is smaller than
+    /// `MIN_AREA_SIZE`, or than the display itself when that is even smaller.
+    pub fn clamped(&self, display_width: u32, display_height: u32) -> Result<Area, AreaError> {
+        if self.x >= display_width || self.y >= display_height {
+            return Err(AreaError::OutOfBounds { x: self.x, y: self.y, display_width, display_height });
+        }
+        let width = self.width.min(display_width - self.x);
+        let height = self.height.min(display_height - self.y);
+        let min = MIN_AREA_SIZE.min(display_width).min(display_height);
+        if width < min || height < min {
+            return Err(AreaError::TooSmall { width, height, min });
+        }
+        Ok(Area::new(self.x, self.y, width, height, self.selected_display))
+    }
 }
-pub fn create(selected_display: u32) -> Capturer {
-    let mut displays = Display::all().expect("Couldn't find displays.");
-    let d= displays.remove(selected_display as usize);
+pub fn create(selected_display: u32) -> Result<Capturer, CaptureError> {
+    let mut displays = Display::all().map_err(|_| CaptureError::NoDisplays)?;
+    if displays.is_empty() {
+        return Err(CaptureError::NoDisplays);
+    }
+    if selected_display as usize >= displays.len() {
+        return Err(CaptureError::DisplayNotFound { selected: selected_display, available: displays.len() as u32 });
+    }
+    let d = displays.remove(selected_display as usize);
     println!("Selected display: {}, resolution: {}*{}", selected_display, d.height(), d.width());
-    Capturer::new(d).expect("Couldn't begin capture.")
+    Capturer::new(d).map_err(|error| CaptureError::Start { display: selected_display, error })
+}
+pub fn open(kind: &SourceKind, selected_display: u32) -> Result<Box<dyn FrameSource>, CaptureError> {
+    Ok(match kind {
+        SourceKind::Screen => Box::new(ScreenSource::open(selected_display)?),
+        SourceKind::Desktop { offsets } => Box::new(DesktopSource::open(offsets)?),
+        SourceKind::TestPattern { width, height } => Box::new(TestPattern::new(*width, *height)),
+        SourceKind::Replay { path, looping } => Box::new(ReplaySource::open(path, *looping)?),
+    })
 }
-pub fn capture(cpt: &mut Capturer) -> Vec<u8> {
+/// Waits for the next frame of `display` and copies it to `data`, reusing its allocation.
+/// Gives up with `DisplayLost` after `MAX_CAPTURE_ERRORS` consecutive errors, or with `Stalled`
+/// when nothing new comes within `STALL_TIMEOUT`; `data` is left untouched then.
+pub fn capture(cpt: &mut Capturer, display: u32, data: &mut Vec<u8>) -> Result<(), CaptureError> {
+    let start = Instant::now();
+    let mut errors = 0;
     loop {
         match cpt.frame() {
             Ok(buffer) => {
-                return buffer.to_vec();
+                data.clear();
+                data.extend_from_slice(&buffer);
+                return Ok(());
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                if start.elapsed() >= STALL_TIMEOUT {
+                    return Err(CaptureError::Stalled { display });
+                }
             }
             Err(error) => {
-                if error.kind() != std::io::ErrorKind::WouldBlock {
-                    println!("Error: {}", error);
+                errors += 1;
+                println!("Capture error {errors}/{MAX_CAPTURE_ERRORS} on display {display}: {error}");
+                // an unplugged display disappears from the list, no need to insist
+                let available = Display::all().map(|d| d.len()).unwrap_or(0);
+                if errors >= MAX_CAPTURE_ERRORS || display as usize >= available {
+                    return Err(CaptureError::DisplayLost { display, error });
                 }
-                std::thread::sleep(FPS_SLEEP);
-                continue;
             }
         };
+        thread::sleep(FPS_SLEEP);
     }
 }
-pub fn u8x4_crop(frame: Frame, area: &Area) -> Frame {
-    if frame.data.len() == (area.width * area.height) as usize * 4 {
-        return frame;
+/// Crops a frame, honouring its stride, and converts it to tightly packed `format` in a single
+/// pass. The area is clamped to the frame. The result is written to `buffer`, whatever its
+/// content, so that a buffer recycled from a `BufferPool` spares an allocation.
+pub fn crop_convert(frame: &Frame, area: &Area, format: PixelFormat, buffer: Vec<u8>) -> Frame {
+    // every pair of formats gets its own loop, going through RGB, rather than a match per pixel
+    match frame.format {
+        PixelFormat::Bgra => crop_from::<4>(frame, area, format, buffer, |p| [p[2], p[1], p[0]]),
+        PixelFormat::Rgba => crop_from::<4>(frame, area, format, buffer, |p| [p[0], p[1], p[2]]),
+        PixelFormat::Rgb => crop_from::<3>(frame, area, format, buffer, |p| *p),
     }
-    let mut rgba = image::RgbaImage::from_raw(frame.w, frame.h, frame.data).unwrap();
-    let sub_rgba = image::imageops::crop(&mut rgba, area.x, area.y, area.width, area.height);
-    Frame::new(area.width, area.height, sub_rgba.to_image().to_vec())
 }
-pub fn from_bgra_to_rgb(frame_data: Vec<u8>) -> Vec<u8> {
-    let width = frame_data.len();
-    let width_without_alpha = (width / 4) * 3;
+/// Converts a whole frame to tightly packed `format`, see `crop_convert`.
+pub fn convert(frame: &Frame, format: PixelFormat, buffer: Vec<u8>) -> Frame {
+    crop_convert(frame, &Area::new(0, 0, frame.w, frame.h, 0), format, buffer)
+}
+// `rgb` reads a pixel of `frame`, which has S bytes per pixel
+fn crop_from<const S: usize>(frame: &Frame, area: &Area, format: PixelFormat, buffer: Vec<u8>, rgb: impl Fn(&[u8; S]) -> [u8; 3]) -> Frame {
+    if frame.format == format {
+        return crop_map::<S, S>(frame, area, buffer, |p| *p);
+    }
+    let mut frame = match format {
+        PixelFormat::Bgra => crop_map::<S, 4>(frame, area, buffer, |p| PixelFormat::Bgra.encode(rgb(p))),
+        PixelFormat::Rgba => crop_map::<S, 4>(frame, area, buffer, |p| PixelFormat::Rgba.encode(rgb(p))),
+        PixelFormat::Rgb => crop_map::<S, 3>(frame, area, buffer, rgb),
+    };
+    frame.format = format;
+    frame
+}
+// writes `f` of each pixel of the area, S bytes per pixel in and D out, keeping the format
+fn crop_map<const S: usize, const D: usize>(frame: &Frame, area: &Area, mut buffer: Vec<u8>, f: impl Fn(&[u8; S]) -> [u8; D]) -> Frame {
+    let x = area.x.min(frame.w);
+    let y = area.y.min(frame.h);
+    let w = area.width.min(frame.w - x);
+    let h = area.height.min(frame.h - y);
+    let (s, d) = (S as u32, D as u32);
+    buffer.resize((w * h * d) as usize, 0);
+    for (row, dst_row) in buffer.chunks_exact_mut((w * d).max(1) as usize).enumerate() {
+        let start = ((y + row as u32) * frame.stride + x * s) as usize;
+        let src_row = &frame.data[start..start + (w * s) as usize];
+        for (src, dst) in src_row.chunks_exact(S).zip(dst_row.chunks_exact_mut(D)) {
+            dst.copy_from_slice(&f(src.try_into().unwrap()));
+        }
+    }
+    Frame::with_stride(w, h, w * d, frame.format, buffer)
+}
+/// Resizes a frame to `width`x`height`. The filters of `image` work on RGB or RGBA: a frame with
+/// padded rows is packed first, and a BGRA one is converted, so that the result is RGBA.
+pub fn resize(frame: &Frame, width: u32, height: u32, filter: FilterType) -> Frame {
+    let len = (frame.w * frame.h * frame.format.bytes_per_pixel()) as usize;
+    match frame.format {
+        PixelFormat::Rgb if frame.is_packed() => {
+            let rgb = image::ImageBuffer::<image::Rgb<u8>, &[u8]>::from_raw(frame.w, frame.h, &frame.data[..len]).unwrap();
+            Frame::new(width, height, PixelFormat::Rgb, image::imageops::resize(&rgb, width, height, filter).into_raw())
+        }
+        PixelFormat::Rgba if frame.is_packed() => {
+            let rgba = image::ImageBuffer::<image::Rgba<u8>, &[u8]>::from_raw(frame.w, frame.h, &frame.data[..len]).unwrap();
+            Frame::new(width, height, PixelFormat::Rgba, image::imageops::resize(&rgba, width, height, filter).into_raw())
+        }
+        PixelFormat::Bgra | PixelFormat::Rgba => resize(&convert(frame, PixelFormat::Rgba, Vec::new()), width, height, filter),
+        PixelFormat::Rgb => resize(&convert(frame, PixelFormat::Rgb, Vec::new()), width, height, filter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A BGRA frame whose pixel (x, y) is [x, y, x + y, 255], with `padding` garbage bytes
+    /// at the end of every row.
+    fn padded_frame(w: u32, h: u32, padding: u32) -> Frame {
+        let stride = w * 4 + padding;
+        let mut data = vec![0xAA; (stride * h) as usize];
+        for y in 0..h {
+            for x in 0..w {
+                let i = (y * stride + x * 4) as usize;
+                data[i..i + 4].copy_from_slice(&[x as u8, y as u8, (x + y) as u8, 255]);
+            }
+        }
+        Frame::with_stride(w, h, stride, PixelFormat::Bgra, data)
+    }
+
+    #[test]
+    fn crop_honours_stride() {
+        for padding in [0, 4, 12, 60, 256] {
+            let frame = padded_frame(37, 21, padding);
+            let rgb = crop_convert(&frame, &Area::new(5, 3, 20, 11, 0), PixelFormat::Rgb, Vec::new());
+            assert_eq!((rgb.w, rgb.h, rgb.stride), (20, 11, 60));
+            assert_eq!(rgb.data.len(), 20 * 11 * 3);
+            for y in 0..11 {
+                for x in 0..20 {
+                    let i = ((y * 20 + x) * 3) as usize;
+                    assert_eq!(rgb.data[i..i + 3], [(x + y + 8) as u8, (y + 3) as u8, (x + 5) as u8], "padding {padding}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conversion_honours_stride() {
+        for padding in [0, 4, 12, 60, 256] {
+            let frame = padded_frame(37, 21, padding);
+            assert!(frame.is_consistent());
+            // a recycled buffer, bigger than needed and dirty
+            let rgb = crop_convert(&frame, &Area::new(0, 0, 37, 21, 0), PixelFormat::Rgb, vec![0x55; 10_000]);
+            assert_eq!((rgb.w, rgb.h, rgb.stride), (37, 21, 37 * 3));
+            assert_eq!(rgb.data.len(), 37 * 21 * 3);
+            for y in 0..21 {
+                for x in 0..37 {
+                    let i = ((y * 37 + x) * 3) as usize;
+                    assert_eq!(rgb.data[i..i + 3], [(x + y) as u8, y as u8, x as u8], "padding {padding}");
+                }
+            }
+        }
+    }
 
-    let mut data: Vec<u8> = vec![0; width_without_alpha];
+    #[test]
+    fn area_is_clamped_to_the_display() {
+        let area = Area::new(100, 50, 4000, 300, 1).clamped(1920, 1080).unwrap();
+        assert_eq!((area.x, area.y, area.width, area.height, area.selected_display), (100, 50, 1820, 300, 1));
+        let area = Area::new(0, 0, 1920, 1080, 0).clamped(1920, 1080).unwrap();
+        assert_eq!((area.width, area.height), (1920, 1080));
+    }
 
-    for (src, dst) in frame_data.chunks_exact(4).zip(data.chunks_exact_mut(3)) {
-        dst[0] = src[2];
-        dst[1] = src[1];
-        dst[2] = src[0];
+    #[test]
+    fn invalid_areas_are_rejected() {
+        assert_eq!(
+            Area::new(1920, 0, 100, 100, 0).clamped(1920, 1080).unwrap_err(),
+            AreaError::OutOfBounds { x: 1920, y: 0, display_width: 1920, display_height: 1080 }
+        );
+        assert_eq!(
+            Area::new(0, 0, 0, 100, 0).clamped(1920, 1080).unwrap_err(),
+            AreaError::TooSmall { width: 0, height: 100, min: MIN_AREA_SIZE }
+        );
+        // trimming can leave too little
+        assert!(Area::new(1910, 0, 100, 100, 0).clamped(1920, 1080).is_err());
+        // a display smaller than the minimum can still be cast whole
+        assert!(Area::new(0, 0, 8, 8, 0).clamped(8, 8).is_ok());
     }
 
-    return data;
-}
\ No newline at end of file
+    #[test]
+    fn output_size_keeps_the_aspect_ratio_and_never_enlarges() {
+        assert_eq!(OutputSize::Height(720).target(3840, 2160, 1.0), (1280, 720));
+        assert_eq!(OutputSize::Height(1080).target(1280, 720, 1.0), (1280, 720));
+        assert_eq!(OutputSize::Scale(0.5).target(1920, 1080, 0.25), (960, 540));
+        assert_eq!(OutputSize::FitToBandwidth.target(1920, 1080, 0.75), (1440, 810));
+        assert_eq!(OutputSize::FitToBandwidth.target(1920, 1080, 1.0), (1920, 1080));
+    }
+
+    #[test]
+    fn crop_is_clamped_to_the_frame() {
+        let rgb = crop_convert(&padded_frame(16, 8, 8), &Area::new(10, 4, 100, 100, 0), PixelFormat::Rgb, Vec::new());
+        assert_eq!((rgb.w, rgb.h), (6, 4));
+        assert_eq!(rgb.data[0..3], [14, 4, 10]);
+    }
+
+    #[test]
+    fn formats_convert_both_ways() {
+        let bgra = padded_frame(9, 5, 12);
+        for format in [PixelFormat::Bgra, PixelFormat::Rgba, PixelFormat::Rgb] {
+            let converted = convert(&bgra, format, Vec::new());
+            assert_eq!(converted.format, format);
+            assert!(converted.is_packed() && converted.is_consistent());
+            let back = converted.into_format(PixelFormat::Bgra);
+            for y in 0..5 {
+                assert_eq!(bgra.row(y), back.row(y), "{format:?}");
+            }
+        }
+        let rgba = convert(&bgra, PixelFormat::Rgba, Vec::new());
+        assert_eq!(rgba.row(1)[8..12], [3, 1, 2, 255]);
+        // resizing works on the channels it expects: BGRA comes back as RGBA
+        let scaled = resize(&bgra, 3, 5, FilterType::Nearest);
+        assert_eq!(scaled.format, PixelFormat::Rgba);
+        assert_eq!(scaled.row(0)[..4], rgba.row(0)[4..8]);
+    }
+}
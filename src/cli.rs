@@ -0,0 +1,332 @@
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::time::Instant;
+use crate::capturer;
+use crate::capturer::{Area, Frame, OutputSize, PixelFormat, SourceKind};
+use crate::clip::{self, ClipRange, GifOptions};
+use crate::depth::ColorDepth;
+use crate::mask::{Mask, MaskStyle};
+use crate::overlay::OverlayOptions;
+use crate::pipe::{PipeFormat, PipeSink};
+use crate::pool::BufferPool;
+use crate::recorder::RecordSettings;
+use crate::tracks::{RecordMode, TrackLayout};
+use crate::sender::CastOptions;
+use crate::{receiver, sender};
+
+pub const USAGE: &str = "usage:
+  screencasting_app receive [--output <file|fifo|->] [--format raw|mjpeg] [--track <n>]
+  screencasting_app send <receiver ip> [--source screen[:<display>]|desktop|pattern[:<width>x<height>]|replay:<path>] [--loop]
+                    [--size fit|1080p|720p|50%|original] [--mask <x>,<y>,<width>,<height>[:pixelate]]...
+                    [--watermark <caster name>] [--logo <image>] [--follow <width>x<height>]
+                    [--depth full|565|palette|grey]
+  screencasting_app gif <recording> [--output <file>] [--last <s>|--from <s> --to <s>] [--fps <n>] [--scale <percent>]
+  screencasting_app bench [--frames <n>]";
+
+/// Runs the subcommand given on the command line without the gui.
+/// Returns `None` if there is no subcommand, so that the gui should start.
+pub fn run(args: &[String]) -> Option<Result<(), String>> {
+    match args.first().map(String::as_str) {
+        Some("receive") => Some(receive(&args[1..])),
+        Some("send") => Some(send(&args[1..])),
+        Some("gif") => Some(gif(&args[1..])),
+        Some("bench") => Some(bench(&args[1..])),
+        _ => None,
+    }
+}
+
+/// Writes the frames received to the output instead of the gui.
+/// The tracks of the cast are written side by side, unless a single one is asked for.
+fn receive(args: &[String]) -> Result<(), String> {
+    let mut output = "-".to_string();
+    let mut format = PipeFormat::Raw;
+    let mut layout = TrackLayout::SideBySide;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => {
+                output = args.next().ok_or("missing value for --output")?.clone();
+            }
+            "--format" | "-f" => {
+                format = match args.next().map(String::as_str) {
+                    Some("raw") => PipeFormat::Raw,
+                    Some("mjpeg") => PipeFormat::Mjpeg,
+                    _ => return Err("--format must be raw or mjpeg".to_string()),
+                };
+            }
+            "--track" | "-t" => {
+                let track = args.next().and_then(|t| t.parse().ok()).ok_or("--track must be a track number")?;
+                layout = TrackLayout::Single(track);
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+
+    let sink = PipeSink::open(&output, format).map_err(|e| format!("impossible opening {output}: {e}"))?;
+    // nobody answers from the command line: messages never arrive and events are discarded
+    let (_msg_s, msg_r) = channel();
+    let (event_s, _) = channel();
+    if let Some(finishing) = receiver::start(sink, msg_r, event_s, false, layout, RecordMode::default(), RecordSettings::default()) {
+        let _ = finishing.join();
+    }
+    Ok(())
+}
+
+/// Casts the whole source to the receiver.
+fn send(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    let ip_addr = args.next().ok_or("missing receiver ip")?.clone();
+    let mut source_kind = SourceKind::Screen;
+    let mut selected_display = 0;
+    let mut looping = false;
+    let mut options = CastOptions::default();
+    let mut follow_size = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--source" | "-s" => {
+                let value = args.next().ok_or("missing value for --source")?;
+                (source_kind, selected_display) = parse_source(value)?;
+            }
+            "--loop" => looping = true,
+            "--size" => {
+                let value = args.next().ok_or("missing value for --size")?;
+                options.output_size = parse_output_size(value)?;
+            }
+            "--depth" => {
+                options.color_depth = match args.next().map(String::as_str) {
+                    Some("full") => ColorDepth::Full,
+                    Some("565") => ColorDepth::Rgb565,
+                    Some("palette") => ColorDepth::Palette,
+                    Some("grey") => ColorDepth::Grey,
+                    _ => return Err("--depth must be full, 565, palette or grey".to_string()),
+                };
+            }
+            "--mask" => {
+                let value = args.next().ok_or("missing value for --mask")?;
+                options.masks.push(parse_mask(value)?);
+            }
+            "--follow" => {
+                let value = args.next().ok_or("missing value for --follow")?;
+                follow_size = Some(parse_size(value)?);
+                options.follow_mouse = true;
+            }
+            "--watermark" => {
+                let caster = args.next().ok_or("missing value for --watermark")?.clone();
+                options.overlay.get_or_insert_with(OverlayOptions::default).caster = caster;
+            }
+            "--logo" => {
+                let logo = args.next().ok_or("missing value for --logo")?;
+                options.overlay.get_or_insert_with(OverlayOptions::default).logo = Some(PathBuf::from(logo));
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+    if let SourceKind::Replay { looping: l, .. } = &mut source_kind {
+        *l = looping;
+    }
+    for mask in options.masks.iter_mut() {
+        mask.area.selected_display = selected_display;
+    }
+
+    let source = capturer::open(&source_kind, selected_display).map_err(|e| format!("impossible opening the source: {e}"))?;
+    let (width, height) = follow_size.unwrap_or((source.width(), source.height()));
+    let area = Area::new(0, 0, width, height, selected_display);
+    drop(source);
+    // nobody can pick another display from here, so the sender gives up when the source is lost
+    let (_, msg_r) = channel();
+    let (event_s, _) = channel();
+    sender::start(ip_addr, source_kind, vec![area], options, msg_r, event_s);
+    Ok(())
+}
+
+/// Cuts a clip of a recording as an animated GIF, the last 10 s unless a range is given.
+fn gif(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    let recording = PathBuf::from(args.next().ok_or("missing recording")?);
+    let mut output = recording.with_extension("gif");
+    let mut options = GifOptions::default();
+    let (mut last, mut from, mut to) = (Some(10_000), None, None);
+    // seconds, in ms
+    let seconds = |arg: &str, value: Option<&String>| {
+        value.and_then(|v| v.parse::<f64>().ok()).filter(|s| *s >= 0.0).map(|s| (s * 1000.0) as u64).ok_or(format!("{arg} must be a number of seconds"))
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => {
+                output = PathBuf::from(args.next().ok_or("missing value for --output")?);
+            }
+            "--last" => last = Some(seconds(arg, args.next())?),
+            "--from" => (last, from) = (None, Some(seconds(arg, args.next())?)),
+            "--to" => (last, to) = (None, Some(seconds(arg, args.next())?)),
+            "--fps" => {
+                options.fps = args.next().and_then(|n| n.parse().ok()).filter(|n| (1..=clip::MAX_FPS).contains(n)).ok_or(format!("--fps must be from 1 to {}", clip::MAX_FPS))?;
+            }
+            "--scale" => {
+                let value = args.next().map(|v| v.trim_end_matches('%'));
+                options.scale = value.and_then(|n| n.parse().ok()).filter(|n| (1..=100).contains(n)).ok_or("--scale must be a percentage from 1 to 100")?;
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+    let range = match last {
+        Some(last) => ClipRange::Last(last),
+        None => ClipRange::Between(from.unwrap_or(0), to.unwrap_or(u64::MAX)),
+    };
+    let frames = clip::export(&recording, range, &options, &output).map_err(|e| format!("impossible exporting the clip: {e}"))?;
+    eprintln!("Saved {frames} frames as {}", output.display());
+    Ok(())
+}
+
+/// Measures the per-frame cost of the sender pipeline, from the copy of the captured buffer to the
+/// RGB frame ready to send, on synthetic frames with padded rows as some capture backends give.
+fn bench(args: &[String]) -> Result<(), String> {
+    let mut frames = 100;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" | "-n" => {
+                frames = args.next().and_then(|n| n.parse().ok()).filter(|&n| n > 0).ok_or("--frames must be a positive number")?;
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+
+    // milliseconds per frame of `f`, run `frames` times
+    let time = |f: &mut dyn FnMut()| {
+        let start = Instant::now();
+        for _ in 0..frames {
+            f();
+        }
+        (start.elapsed() / frames).as_secs_f64() * 1000.0
+    };
+    for (w, h) in [(1920, 1080), (3840, 2160)] {
+        let stride = w * 4 + 64;
+        let captured: Vec<u8> = (0..stride * h).map(|i| i as u8).collect();
+        let mut frame = Frame::with_stride(w, h, stride, PixelFormat::Bgra, Vec::new());
+        let area = Area::new(0, 0, w, h, 0);
+        let mut pool = BufferPool::new();
+
+        let copy = time(&mut || {
+            frame.data.clear();
+            frame.data.extend_from_slice(black_box(&captured));
+        });
+        let pooled = time(&mut || {
+            let rgb = capturer::crop_convert(black_box(&frame), &area, PixelFormat::Rgb, pool.take());
+            pool.give(black_box(rgb).data);
+        });
+        let allocating = time(&mut || {
+            black_box(capturer::crop_convert(black_box(&frame), &area, PixelFormat::Rgb, Vec::new()));
+        });
+        println!(
+            "{w}x{h}: copy of the capture {copy:.2} ms, crop and conversion {pooled:.2} ms with pooled buffers, {allocating:.2} ms allocating them ({:.0} fps at most)",
+            1000.0 / (copy + pooled)
+        );
+    }
+    Ok(())
+}
+
+fn parse_output_size(value: &str) -> Result<OutputSize, String> {
+    match value {
+        "fit" => Ok(OutputSize::FitToBandwidth),
+        _ => OutputSize::PRESETS
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(value))
+            .map(|(size, _)| *size)
+            .ok_or(format!("unknown size {value}")),
+    }
+}
+
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    value
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|&(w, h)| w > 0 && h > 0)
+        .ok_or(format!("invalid resolution {value}"))
+}
+
+fn parse_mask(value: &str) -> Result<Mask, String> {
+    let (rect, style) = match value.split_once(':') {
+        None => (value, MaskStyle::Solid),
+        Some((rect, "pixelate")) => (rect, MaskStyle::Pixelate),
+        Some((_, style)) => return Err(format!("unknown mask style {style}")),
+    };
+    let numbers: Vec<u32> = rect.split(',').map(str::parse).collect::<Result<_, _>>().map_err(|_| format!("invalid mask {value}"))?;
+    let [x, y, width, height] = numbers[..] else { return Err(format!("invalid mask {value}")); };
+    Ok(Mask { area: Area::new(x, y, width, height, 0), style })
+}
+
+fn parse_source(value: &str) -> Result<(SourceKind, u32), String> {
+    let (name, param) = value.split_once(':').map_or((value, None), |(n, p)| (n, Some(p)));
+    match (name, param) {
+        ("screen", None) => Ok((SourceKind::Screen, 0)),
+        ("screen", Some(display)) => {
+            let display = display.parse().map_err(|_| format!("invalid display {display}"))?;
+            Ok((SourceKind::Screen, display))
+        }
+        ("desktop", None) => Ok((SourceKind::Desktop { offsets: Vec::new() }, 0)),
+        ("pattern", None) => Ok((SourceKind::TestPattern { width: 1920, height: 1080 }, 0)),
+        ("pattern", Some(size)) => {
+            let (width, height) = parse_size(size)?;
+            Ok((SourceKind::TestPattern { width, height }, 0))
+        }
+        ("replay", Some(path)) => Ok((SourceKind::Replay { path: PathBuf::from(path), looping: false }, 0)),
+        _ => Err(format!("unknown source {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_are_parsed() {
+        let accepted = [
+            ("screen", SourceKind::Screen, 0),
+            ("screen:2", SourceKind::Screen, 2),
+            ("desktop", SourceKind::Desktop { offsets: Vec::new() }, 0),
+            ("pattern", SourceKind::TestPattern { width: 1920, height: 1080 }, 0),
+            ("pattern:640x480", SourceKind::TestPattern { width: 640, height: 480 }, 0),
+            ("replay:./a:b.avi", SourceKind::Replay { path: PathBuf::from("./a:b.avi"), looping: false }, 0),
+        ];
+        for (value, kind, display) in accepted {
+            assert_eq!(parse_source(value), Ok((kind, display)), "{value}");
+        }
+        for value in ["", "window", "screen:first", "desktop:1", "pattern:640", "pattern:0x480", "replay"] {
+            assert!(parse_source(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn sizes_are_parsed() {
+        let accepted = [
+            ("fit", OutputSize::FitToBandwidth),
+            ("1080p", OutputSize::Height(1080)),
+            ("720P", OutputSize::Height(720)),
+            ("50%", OutputSize::Scale(0.5)),
+            ("original", OutputSize::Scale(1.0)),
+        ];
+        for (value, size) in accepted {
+            assert_eq!(parse_output_size(value), Ok(size), "{value}");
+        }
+        for value in ["", "480p", "25%", "big"] {
+            assert!(parse_output_size(value).is_err(), "{value}");
+        }
+        assert_eq!(parse_size("1280x720"), Ok((1280, 720)));
+        for value in ["1280", "1280x", "x720", "0x720", "-1x720", "1280 x 720"] {
+            assert!(parse_size(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn masks_are_parsed() {
+        let accepted = [("10,20,300,40", (10, 20, 300, 40), MaskStyle::Solid), ("0,0,1,1:pixelate", (0, 0, 1, 1), MaskStyle::Pixelate)];
+        for (value, (x, y, width, height), style) in accepted {
+            let mask = parse_mask(value).unwrap();
+            assert_eq!((mask.area.x, mask.area.y, mask.area.width, mask.area.height, mask.style), (x, y, width, height, style), "{value}");
+        }
+        for value in ["", "10,20,300", "10,20,300,40,5", "10,20,300,-40", "a,b,c,d", "10,20,300,40:blur"] {
+            assert!(parse_mask(value).is_err(), "{value}");
+        }
+    }
+}
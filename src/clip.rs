@@ -0,0 +1,409 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::ColorType;
+use serde::{Deserialize, Serialize};
+use crate::capturer::{self, Frame, PixelFormat};
+use crate::depth::PaletteBuilder;
+use crate::replay::ReplaySource;
+
+/// GIF delays are in hundredths of a second, and most viewers slow down anything faster than 50 fps.
+pub const MAX_FPS: u32 = 50;
+/// How long the receiver keeps the frames it received, for clips.
+pub const MAX_CLIP_SECS: u32 = 300;
+// the memory the frames kept may take, compressed: the oldest go first when they take more
+const MAX_KEPT_BYTES: usize = 256 * 1024 * 1024;
+const KEPT_QUALITY: u8 = 90;
+// frames waiting to be compressed; beyond this they are dropped rather than slowing the receiver
+const QUEUE_LEN: usize = 4;
+
+/// The part of a recording a clip is cut from, in ms from its start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClipRange {
+    /// The last ms of the recording.
+    Last(u64),
+    /// From the first timestamp to the second one.
+    Between(u64, u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GifOptions {
+    pub fps: u32,
+    pub scale: u32, // % of the size recorded
+}
+
+impl Default for GifOptions {
+    fn default() -> Self {
+        Self { fps: 10, scale: 50 }
+    }
+}
+
+/// Cuts `range` from the recording at `source`, anything a replay can open, as an animated GIF in
+/// `output`. Returns the number of frames the GIF has.
+///
+/// The recording is sampled at the fps asked for. Each frame has a palette of its own, and only
+/// the rectangle that changed since the previous one is stored, with the pixels that did not change
+/// in it left transparent; a frame without change makes the previous one last longer.
+pub fn export(source: &Path, range: ClipRange, options: &GifOptions, output: &Path) -> io::Result<usize> {
+    let mut replay = ReplaySource::open(source, false)?;
+    let timestamps = replay.timestamps().to_vec();
+    // a replay always has a frame
+    let end = timestamps[timestamps.len() - 1];
+    let (from, to) = match range {
+        ClipRange::Last(duration) => (end.saturating_sub(duration), end),
+        ClipRange::Between(from, to) if from <= to => (from.min(end), to.min(end)),
+        ClipRange::Between(..) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "the clip ends before it starts")),
+    };
+    encode(&timestamps, from, to, |i| replay.decode(i), options, output)
+}
+
+// writes the frames showing from `from` to `to`, in ms, as a GIF; `decode` gives the frame that
+// shows from each of the `timestamps`
+fn encode(timestamps: &[u64], from: u64, to: u64, mut decode: impl FnMut(usize) -> io::Result<Frame>, options: &GifOptions, output: &Path) -> io::Result<usize> {
+    let step = 1000 / options.fps.clamp(1, MAX_FPS) as u64;
+
+    // the frame showing at each step, once
+    let mut picks: Vec<(u64, usize)> = Vec::new();
+    for t in (from..=to).step_by(step as usize) {
+        let i = timestamps.partition_point(|&ts| ts <= t).saturating_sub(1);
+        if picks.last().is_none_or(|&(_, last)| last != i) {
+            picks.push((t, i));
+        }
+    }
+
+    let mut writer = GifWriter::create(output)?;
+    let mut size = None;
+    for (k, &(t, i)) in picks.iter().enumerate() {
+        // hundredths of a second from the start of the clip, so that rounding errors do not add up
+        let next = picks.get(k + 1).map_or(t + step, |&(next, _)| next);
+        let delay = ((next - from) / 10 - (t - from) / 10) as u16;
+        let frame = decode(i)?.into_format(PixelFormat::Rgb);
+        // the first frame gives the size of the GIF, e.g. when a track joins the cast meanwhile
+        let (w, h) = *size.get_or_insert(((frame.w * options.scale / 100).max(1), (frame.h * options.scale / 100).max(1)));
+        let frame = if (w, h) == (frame.w, frame.h) { frame } else { capturer::resize(&frame, w, h, FilterType::Triangle) };
+        writer.write(&frame, delay)?;
+    }
+    writer.finish()
+}
+
+/// The frames the receiver got lately, compressed on a thread of their own, for clips cut without
+/// saving the cast. They are kept for `MAX_CLIP_SECS`, or less when they take too much memory.
+pub struct ClipBuffer {
+    kept: Arc<Mutex<KeptFrames>>,
+    frame_s: SyncSender<(Instant, Frame)>,
+}
+
+impl ClipBuffer {
+    pub fn start() -> Self {
+        let kept = Arc::new(Mutex::new(KeptFrames::default()));
+        let (frame_s, frame_r) = sync_channel::<(Instant, Frame)>(QUEUE_LEN);
+        let shared = kept.clone();
+        // ends when the buffer is dropped
+        thread::spawn(move || {
+            for (received, frame) in frame_r {
+                let frame = frame.to_format(PixelFormat::Rgb);
+                let mut jpeg = Vec::new();
+                match JpegEncoder::new_with_quality(&mut jpeg, KEPT_QUALITY).encode(&frame.data, frame.w, frame.h, ColorType::Rgb8) {
+                    Ok(_) => shared.lock().unwrap().push(received, jpeg.into()),
+                    Err(e) => eprintln!("Impossible keeping a frame for clips: {e}"),
+                }
+            }
+        });
+        Self { kept, frame_s }
+    }
+    /// Keeps `frame`, received now, unless the frames before it are still being compressed.
+    pub fn record(&self, frame: &Frame) {
+        let _ = self.frame_s.try_send((Instant::now(), frame.clone()));
+    }
+    /// The frames showing from `from` to `to`, as far back as they are kept.
+    pub fn cut(&self, from: Instant, to: Instant) -> io::Result<Clip> {
+        self.kept.lock().unwrap().cut(from, to)
+    }
+}
+
+#[derive(Clone)]
+struct KeptFrame {
+    received: Instant,
+    jpeg: Arc<[u8]>, // shared with the clips cut meanwhile
+}
+
+#[derive(Default)]
+struct KeptFrames {
+    frames: VecDeque<KeptFrame>,
+    bytes: usize,
+}
+
+impl KeptFrames {
+    fn push(&mut self, received: Instant, jpeg: Arc<[u8]>) {
+        self.bytes += jpeg.len();
+        self.frames.push_back(KeptFrame { received, jpeg });
+        let oldest = received.checked_sub(Duration::from_secs(MAX_CLIP_SECS as u64));
+        while self.frames.len() > 1 && (self.bytes > MAX_KEPT_BYTES || oldest.is_some_and(|oldest| self.frames[0].received < oldest)) {
+            let frame = self.frames.pop_front().unwrap();
+            self.bytes -= frame.jpeg.len();
+        }
+    }
+    fn cut(&self, from: Instant, to: Instant) -> io::Result<Clip> {
+        if to < from {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the clip ends before it starts"));
+        }
+        // the frame showing at `from`, and the ones after it until `to`
+        let first = self.frames.partition_point(|f| f.received <= from).saturating_sub(1);
+        let frames: Vec<KeptFrame> = self.frames.range(first..).take_while(|f| f.received <= to).cloned().collect();
+        // up to the last frame kept, when the cast stopped before the end of the clip
+        let to = self.frames.back().map_or(to, |last| to.min(last.received));
+        match frames.first() {
+            Some(first) => Ok(Clip { from: from.max(first.received), to: to.max(first.received), frames }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no frame received in that time")),
+        }
+    }
+}
+
+/// Frames cut from a `ClipBuffer`, to export as a GIF.
+pub struct Clip {
+    from: Instant,
+    to: Instant,
+    frames: Vec<KeptFrame>,
+}
+
+impl Clip {
+    /// Writes the clip as an animated GIF in `output`, as `export` does. Returns the number of
+    /// frames the GIF has.
+    pub fn export(&self, options: &GifOptions, output: &Path) -> io::Result<usize> {
+        let ms = |instant: Instant| instant.saturating_duration_since(self.from).as_millis() as u64;
+        let timestamps: Vec<u64> = self.frames.iter().map(|f| ms(f.received)).collect();
+        let decode = |i: usize| -> io::Result<Frame> {
+            let rgb = image::load_from_memory(&self.frames[i].jpeg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?.into_rgb8();
+            Ok(Frame::new(rgb.width(), rgb.height(), PixelFormat::Rgb, rgb.into_raw()))
+        };
+        encode(&timestamps, 0, ms(self.to), decode, options, output)
+    }
+}
+
+// Writes frames of the same size as a GIF, each over the previous one.
+struct GifWriter {
+    file: Option<BufWriter<File>>, // until the first frame gives the size of the GIF
+    encoder: Option<gif::Encoder<BufWriter<File>>>,
+    previous: Option<Frame>,
+    pending: Option<gif::Frame<'static>>, // written once its delay is known for good
+    frames: usize,
+    palettes: PaletteBuilder,
+}
+
+impl GifWriter {
+    fn create(path: &Path) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self { file: Some(file), encoder: None, previous: None, pending: None, frames: 0, palettes: PaletteBuilder::default() })
+    }
+    // `frame` is RGB and shows for `delay` hundredths of a second
+    fn write(&mut self, frame: &Frame, delay: u16) -> io::Result<()> {
+        let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let (w, h) = (u16::try_from(frame.w).map_err(|_| invalid("too wide for a GIF"))?, u16::try_from(frame.h).map_err(|_| invalid("too high for a GIF"))?);
+        let previous = match &self.previous {
+            Some(previous) if (previous.w, previous.h) != (frame.w, frame.h) => return Err(invalid("the frames of a clip must have the same size")),
+            previous => previous,
+        };
+        let pixel = |frame: &Frame, x: u32, y: u32| -> [u8; 3] {
+            let i = ((y * frame.w + x) * 3) as usize;
+            frame.data[i..i + 3].try_into().unwrap()
+        };
+        let changed = |x: u32, y: u32| previous.as_ref().is_none_or(|p| pixel(p, x, y) != pixel(frame, x, y));
+
+        // the rectangle holding every pixel that changed
+        let (mut left, mut top, mut right, mut bottom) = (frame.w, frame.h, 0, 0);
+        for y in 0..frame.h {
+            for x in 0..frame.w {
+                if changed(x, y) {
+                    (left, top, right, bottom) = (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1));
+                }
+            }
+        }
+        if left >= right {
+            if let Some(pending) = &mut self.pending {
+                pending.delay = pending.delay.saturating_add(delay);
+            }
+            return Ok(());
+        }
+
+        let mut rgb = Vec::new();
+        for y in top..bottom {
+            for x in left..right {
+                if changed(x, y) {
+                    rgb.extend_from_slice(&pixel(frame, x, y));
+                }
+            }
+        }
+        // the last index is left for the pixels that keep the previous frame
+        let palette = self.palettes.build(&rgb, 255);
+        let transparent = palette.colors().len() as u8;
+        let mut indices = Vec::with_capacity(((right - left) * (bottom - top)) as usize);
+        for y in top..bottom {
+            for x in left..right {
+                indices.push(if changed(x, y) { palette.index(pixel(frame, x, y)) } else { transparent });
+            }
+        }
+        let mut colors: Vec<u8> = palette.colors().iter().flatten().copied().collect();
+        colors.extend([0; 3]);
+
+        self.flush()?;
+        if self.encoder.is_none() {
+            let file = self.file.take().expect("a GIF is created with its file");
+            let mut encoder = gif::Encoder::new(file, w, h, &[]).map_err(io::Error::other)?;
+            encoder.set_repeat(gif::Repeat::Infinite).map_err(io::Error::other)?;
+            self.encoder = Some(encoder);
+        }
+        self.pending = Some(gif::Frame {
+            left: left as u16,
+            top: top as u16,
+            width: (right - left) as u16,
+            height: (bottom - top) as u16,
+            delay,
+            dispose: gif::DisposalMethod::Keep,
+            transparent: Some(transparent),
+            palette: Some(colors),
+            buffer: Cow::Owned(indices),
+            ..gif::Frame::default()
+        });
+        self.previous = Some(frame.clone());
+        Ok(())
+    }
+    // writes the pending frame
+    fn flush(&mut self) -> io::Result<()> {
+        if let (Some(frame), Some(encoder)) = (self.pending.take(), &mut self.encoder) {
+            encoder.write_frame(&frame).map_err(io::Error::other)?;
+            self.frames += 1;
+        }
+        Ok(())
+    }
+    fn finish(mut self) -> io::Result<usize> {
+        self.flush()?;
+        // the encoder writes the trailer of the GIF when dropped
+        Ok(self.frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avi::AviWriter;
+
+    #[test]
+    fn clips_keep_only_what_changed() {
+        let dir = std::env::temp_dir();
+        let video = dir.join(format!("clip_test_{}.avi", std::process::id()));
+        let output = video.with_extension("gif");
+        let mut writer = AviWriter::create(&video, 10, 95).unwrap();
+        // a grey screen where a white square appears at 1 s, and nothing changes after 2 s
+        for t in (0..3000).step_by(100) {
+            let mut data = vec![128; 64 * 48 * 3];
+            if t >= 1000 {
+                for y in 8..16 {
+                    data[(y * 64 + 16) * 3..(y * 64 + 24) * 3].fill(255);
+                }
+            }
+            writer.write(t, &Frame::new(64, 48, PixelFormat::Rgb, data)).unwrap();
+        }
+        writer.finish(3000).unwrap();
+
+        // the last 2.5 s at 5 fps, half size
+        let frames = export(&video, ClipRange::Last(2500), &GifOptions { fps: 5, scale: 50 }, &output).unwrap();
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(File::open(&output).unwrap()).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (32, 24));
+        let mut decoded = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            decoded.push((frame.left, frame.top, frame.width, frame.height, frame.delay));
+        }
+        assert_eq!(frames, decoded.len());
+        // the first frame is whole, then only the square is stored, with the blocks of the JPEG
+        // around it; the frames without change last longer, 2.6 s in all: 13 frames at 5 fps
+        assert_eq!(decoded.len(), 2, "{decoded:?}");
+        assert_eq!(decoded[0].2, 32);
+        let (left, top, width, height, _) = decoded[1];
+        assert!(left >= 4 && left + width <= 16 && top + height <= 12, "{decoded:?}");
+        assert_eq!(decoded.iter().map(|f| f.4 as u32).sum::<u32>(), 260);
+
+        assert!(export(&video, ClipRange::Between(2000, 1000), &GifOptions::default(), &output).is_err());
+        std::fs::remove_file(&video).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn kept_frames_are_bounded() {
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+        let mut kept = KeptFrames::default();
+        for s in 0..=MAX_CLIP_SECS as u64 + 10 {
+            kept.push(at(s), vec![0; 10].into());
+        }
+        // the frames older than MAX_CLIP_SECS are gone
+        assert_eq!(kept.frames.len(), MAX_CLIP_SECS as usize + 1);
+        assert_eq!(kept.frames[0].received, at(10));
+        assert_eq!(kept.bytes, kept.frames.len() * 10);
+        // and so are the oldest ones when they take too much memory, but never the last one
+        kept.push(at(400), vec![0; MAX_KEPT_BYTES / 2].into());
+        kept.push(at(401), vec![0; MAX_KEPT_BYTES].into());
+        assert_eq!(kept.frames.len(), 1);
+        assert_eq!(kept.bytes, MAX_KEPT_BYTES);
+    }
+
+    #[test]
+    fn clips_are_cut_from_the_frames_kept() {
+        let output = std::env::temp_dir().join(format!("clip_test_kept_{}.gif", std::process::id()));
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        // a frame every 100 ms, black then white from 1 s; all of them compress alike
+        let mut kept = KeptFrames::default();
+        for t in (0..2000).step_by(100) {
+            let frame = Frame::new(16, 8, PixelFormat::Rgb, vec![if t < 1000 { 0 } else { 255 }; 16 * 8 * 3]);
+            let mut jpeg = Vec::new();
+            JpegEncoder::new_with_quality(&mut jpeg, KEPT_QUALITY).encode(&frame.data, 16, 8, ColorType::Rgb8).unwrap();
+            kept.push(at(t), jpeg.into());
+        }
+
+        // from 0.55 s to 1.55 s at 10 fps: the frame showing at 0.55 s starts the clip
+        let clip = kept.cut(at(550), at(1550)).unwrap();
+        assert_eq!(clip.frames.len(), 11);
+        let frames = clip.export(&GifOptions { fps: 10, scale: 100 }, &output).unwrap();
+        let mut decoder = gif::DecodeOptions::new().read_info(File::open(&output).unwrap()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        // black until 1 s, then white
+        assert_eq!((frames, delays), (2, vec![50, 60]));
+
+        assert!(kept.cut(at(1000), at(500)).is_err());
+        // nothing is kept from before the first frame
+        assert!(KeptFrames::default().cut(at(0), at(100)).is_err());
+        assert_eq!(kept.cut(start.checked_sub(Duration::from_secs(1)).unwrap_or(start), at(50)).unwrap().frames.len(), 1);
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn frames_are_kept_as_they_arrive() {
+        let clips = ClipBuffer::start();
+        let from = Instant::now();
+        clips.record(&Frame::new(4, 4, PixelFormat::Rgb, vec![200; 4 * 4 * 3]));
+        // compressed on the thread of the buffer
+        for _ in 0..100 {
+            if clips.cut(from, Instant::now()).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let clip = clips.cut(from, Instant::now()).unwrap();
+        assert_eq!(clip.frames.len(), 1);
+    }
+}
@@ -0,0 +1,292 @@
+use serde::{Deserialize, Serialize};
+use crate::capturer::{Frame, PixelFormat};
+
+/// How many bits of colour the frames are sent with. The fewer, the lighter the frames: text-heavy
+/// screens stay legible with far less than the 24 bits of RGB.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ColorDepth {
+    /// RGB, 24 bits per pixel.
+    #[default]
+    Full,
+    /// 8 bits of luma per pixel.
+    Grey,
+    /// 16 bits per pixel, 5 for red, 6 for green and 5 for blue, little endian.
+    Rgb565,
+    /// 8 bits per pixel, indexing a palette of up to 256 colours picked for each frame; the payload
+    /// starts with the number of colours minus one and the colours in RGB.
+    Palette,
+}
+
+impl ColorDepth {
+    pub const ALL: [(ColorDepth, &'static str); 4] = [
+        (ColorDepth::Full, "Full colour"),
+        (ColorDepth::Rgb565, "16-bit colour"),
+        (ColorDepth::Palette, "256 colours"),
+        (ColorDepth::Grey, "Greyscale"),
+    ];
+    pub fn name(self) -> &'static str {
+        ColorDepth::ALL.iter().find(|(d, _)| *d == self).map_or("", |(_, name)| name)
+    }
+}
+
+/// The payload of an RGB `frame` at `depth`, written into `buffer`, picking palettes with `palettes`.
+pub fn reduce(frame: &Frame, depth: ColorDepth, mut buffer: Vec<u8>, palettes: &mut PaletteBuilder) -> Vec<u8> {
+    debug_assert_eq!(frame.format, PixelFormat::Rgb);
+    buffer.clear();
+    let pixels = frame.data.chunks_exact(3);
+    match depth {
+        ColorDepth::Full => buffer.extend_from_slice(&frame.data),
+        ColorDepth::Grey => {
+            // BT.601 luma, as for YUV
+            buffer.extend(pixels.map(|p| ((77 * p[0] as u32 + 150 * p[1] as u32 + 29 * p[2] as u32 + 128) >> 8) as u8));
+        }
+        ColorDepth::Rgb565 => {
+            for p in pixels {
+                let v = (p[0] as u16 >> 3) << 11 | (p[1] as u16 >> 2) << 5 | p[2] as u16 >> 3;
+                buffer.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        ColorDepth::Palette => {
+            let palette = palettes.build(&frame.data, 256);
+            buffer.push((palette.colors().len() - 1) as u8);
+            buffer.extend(palette.colors().iter().flatten());
+            for p in pixels {
+                let i = palette.index([p[0], p[1], p[2]]);
+                buffer.push(i);
+            }
+        }
+    }
+    buffer
+}
+
+/// The RGB frame of a `width`x`height` payload sent at `depth`, or `None` if the payload does not
+/// fit the size.
+pub fn expand(depth: ColorDepth, width: u32, height: u32, data: Vec<u8>) -> Option<Frame> {
+    // the size comes from the peer: it may not even fit in memory
+    let pixels = (width as usize).checked_mul(height as usize)?;
+    let rgb = match depth {
+        ColorDepth::Full if Some(data.len()) == pixels.checked_mul(3) => data,
+        ColorDepth::Grey if data.len() == pixels => data.iter().flat_map(|&y| [y, y, y]).collect(),
+        ColorDepth::Rgb565 if Some(data.len()) == pixels.checked_mul(2) => data
+            .chunks_exact(2)
+            .flat_map(|c| {
+                let v = u16::from_le_bytes([c[0], c[1]]);
+                let (r, g, b) = ((v >> 11) as u8, (v >> 5 & 0x3F) as u8, (v & 0x1F) as u8);
+                // the high bits are repeated in the low ones, so that white stays white
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            })
+            .collect(),
+        ColorDepth::Palette => {
+            let colors = *data.first()? as usize + 1;
+            let (palette, indices) = data[1..].split_at_checked(colors * 3)?;
+            if indices.len() != pixels {
+                return None;
+            }
+            let mut rgb = Vec::with_capacity(pixels * 3);
+            for &i in indices {
+                rgb.extend_from_slice(palette.get(i as usize * 3..i as usize * 3 + 3)?);
+            }
+            rgb
+        }
+        _ => return None,
+    };
+    Some(Frame::new(width, height, PixelFormat::Rgb, rgb))
+}
+
+// colours are binned on their 5 high bits of each channel
+const BINS: usize = 1 << 15;
+
+fn bin(rgb: [u8; 3]) -> usize {
+    (rgb[0] as usize >> 3) << 10 | (rgb[1] as usize >> 3) << 5 | rgb[2] as usize >> 3
+}
+
+/// Up to 256 colours picked for an image by cutting its colour space in boxes, which maps any
+/// colour to the nearest one.
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+    nearest: Vec<u16>, // palette index of each bin, u16::MAX until it is looked up
+}
+
+impl Palette {
+    pub fn colors(&self) -> &[[u8; 3]] {
+        &self.colors
+    }
+    /// Index of the colour nearest to `rgb`. Colours of the same bin share it, which keeps the
+    /// lookup cheap once the first one was searched for.
+    pub fn index(&mut self, rgb: [u8; 3]) -> u8 {
+        let b = bin(rgb);
+        if self.nearest[b] == u16::MAX {
+            let distance = |c: &[u8; 3]| c.iter().zip(rgb).map(|(a, b)| (*a as i32 - b as i32).pow(2)).sum::<i32>();
+            let (i, _) = self.colors.iter().enumerate().min_by_key(|(_, c)| distance(c)).unwrap_or((0, &[0; 3]));
+            self.nearest[b] = i as u16;
+        }
+        self.nearest[b] as u8
+    }
+}
+
+// bins start..end, and the channel with the widest range of values in them
+#[derive(Clone, Copy)]
+struct ColorBox {
+    start: usize,
+    end: usize,
+    channel: usize,
+    range: u8,
+    middle: u8, // of the range of the channel
+}
+
+impl ColorBox {
+    fn new(bins: &[(u32, [u8; 3])], start: usize, end: usize) -> Self {
+        let (mut min, mut max) = ([u8::MAX; 3], [0; 3]);
+        for (_, rgb) in &bins[start..end] {
+            for c in 0..3 {
+                (min[c], max[c]) = (min[c].min(rgb[c]), max[c].max(rgb[c]));
+            }
+        }
+        let range = |c: usize| max[c].saturating_sub(min[c]);
+        let channel = (0..3).max_by_key(|&c| range(c)).unwrap_or(0);
+        Self { start, end, channel, range: range(channel), middle: ((min[channel] as u16 + max[channel] as u16) / 2) as u8 }
+    }
+}
+
+/// Builds palettes, one for each frame, keeping its tables from one to the next so that a new
+/// palette allocates nothing.
+///
+/// Colours that differ only in the 3 low bits of each channel are merged; otherwise an image with
+/// few enough colours keeps them exactly.
+pub struct PaletteBuilder {
+    counts: Vec<u32>,
+    sums: Vec<[u64; 3]>,
+    bins: Vec<(u32, [u8; 3])>, // pixels and mean colour of the bins used
+    boxes: Vec<ColorBox>,
+    palette: Palette,
+}
+
+impl Default for PaletteBuilder {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BINS],
+            sums: vec![[0; 3]; BINS],
+            bins: Vec::new(),
+            boxes: Vec::new(),
+            palette: Palette { colors: Vec::new(), nearest: vec![u16::MAX; BINS] },
+        }
+    }
+}
+
+impl PaletteBuilder {
+    /// The palette of at most `max_colors` colours for `rgb` pixels, valid until the next one.
+    pub fn build(&mut self, rgb: &[u8], max_colors: usize) -> &mut Palette {
+        let Self { counts, sums, bins, boxes, palette } = self;
+        let max_colors = max_colors.clamp(1, 256);
+        // the mean of the colours of each bin, weighted by how many pixels it has
+        for p in rgb.chunks_exact(3) {
+            let b = bin([p[0], p[1], p[2]]);
+            counts[b] += 1;
+            sums[b].iter_mut().zip(p).for_each(|(s, c)| *s += *c as u64);
+        }
+        bins.clear();
+        for (count, sum) in counts.iter_mut().zip(sums.iter_mut()).filter(|(count, _)| **count > 0) {
+            bins.push((*count, sum.map(|s| (s / *count as u64) as u8)));
+            // the tables are left empty for the next palette
+            (*count, *sum) = (0, [0; 3]);
+        }
+
+        // split the box with the widest channel in the middle of its range, until there are enough
+        // boxes; unlike a split at the median, a few pixels of a distinct colour, e.g. text, get a box
+        boxes.clear();
+        boxes.push(ColorBox::new(bins, 0, bins.len()));
+        while boxes.len() < max_colors {
+            let widest = (0..boxes.len()).filter(|&i| boxes[i].range > 0).max_by_key(|&i| boxes[i].range);
+            let Some(i) = widest else { break };
+            let ColorBox { start, end, channel, middle, .. } = boxes[i];
+            // the bins up to the middle first; values above it exist, as the range is not empty
+            let mut split = start;
+            for j in start..end {
+                if bins[j].1[channel] <= middle {
+                    bins.swap(j, split);
+                    split += 1;
+                }
+            }
+            boxes[i] = ColorBox::new(bins, start, split);
+            boxes.push(ColorBox::new(bins, split, end));
+        }
+
+        palette.colors.clear();
+        palette.colors.extend(boxes.iter().map(|b| {
+            let bins = &bins[b.start..b.end];
+            let total: u64 = bins.iter().map(|(count, _)| *count as u64).sum::<u64>().max(1);
+            let mut sum = [0u64; 3];
+            for (count, rgb) in bins {
+                sum.iter_mut().zip(rgb).for_each(|(s, c)| *s += *count as u64 * *c as u64);
+            }
+            sum.map(|s| ((s + total / 2) / total) as u8)
+        }));
+        palette.nearest.fill(u16::MAX);
+        palette
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(rgb: &[[u8; 3]]) -> Frame {
+        Frame::new(rgb.len() as u32, 1, PixelFormat::Rgb, rgb.concat())
+    }
+
+    #[test]
+    fn depths_expand_back_to_rgb() {
+        let original = frame(&[[0, 0, 0], [255, 255, 255], [200, 100, 50], [128, 128, 128]]);
+        for (depth, size, tolerance) in [(ColorDepth::Full, 12, 0), (ColorDepth::Rgb565, 8, 7), (ColorDepth::Palette, 1 + 12 + 4, 0)] {
+            let payload = reduce(&original, depth, Vec::new(), &mut PaletteBuilder::default());
+            assert_eq!(payload.len(), size, "{depth:?}");
+            let expanded = expand(depth, 4, 1, payload).unwrap();
+            for (a, b) in expanded.data.iter().zip(&original.data) {
+                assert!(a.abs_diff(*b) <= tolerance, "{depth:?}: {:?}", expanded.data);
+            }
+        }
+        let grey = expand(ColorDepth::Grey, 4, 1, reduce(&original, ColorDepth::Grey, Vec::new(), &mut PaletteBuilder::default())).unwrap();
+        assert_eq!(grey.data, [0, 0, 0, 255, 255, 255, 124, 124, 124, 128, 128, 128]);
+        // payloads that do not fit the size are rejected
+        assert!(expand(ColorDepth::Rgb565, 5, 1, vec![0; 8]).is_none());
+        assert!(expand(ColorDepth::Palette, 2, 1, vec![0, 1, 2, 3, 0, 1]).is_none());
+    }
+
+    #[test]
+    fn sizes_too_large_for_memory_are_rejected() {
+        for depth in [ColorDepth::Full, ColorDepth::Grey, ColorDepth::Rgb565, ColorDepth::Palette] {
+            for (width, height) in [(u32::MAX, u32::MAX), (u32::MAX, 2), (65_536, 65_536)] {
+                assert!(expand(depth, width, height, vec![0; 12]).is_none(), "{depth:?} {width}x{height}");
+            }
+        }
+    }
+
+    #[test]
+    fn palette_keeps_the_most_distinct_colours() {
+        // a gradient of red, with some green and blue text
+        let mut pixels: Vec<[u8; 3]> = (0..=255).map(|r| [r, 0, 0]).collect();
+        pixels.extend([[0, 200, 0]; 50]);
+        pixels.extend([[0, 0, 200]; 50]);
+        let mut builder = PaletteBuilder::default();
+        let palette = builder.build(&pixels.concat(), 4);
+        assert_eq!(palette.colors().len(), 4);
+        let green = palette.index([0, 200, 0]);
+        assert_eq!(palette.colors()[green as usize], [0, 200, 0]);
+        let blue = palette.index([0, 0, 200]);
+        assert_eq!(palette.colors()[blue as usize], [0, 0, 200]);
+        assert_ne!(palette.index([250, 0, 0]), palette.index([5, 0, 0]));
+    }
+
+    #[test]
+    fn palettes_are_built_afresh_for_each_frame() {
+        let red = [[200, 0, 0]; 10].concat();
+        let blue = [[0, 0, 200], [0, 0, 100]].concat();
+        let mut builder = PaletteBuilder::default();
+        builder.build(&red, 256);
+        // nothing of the previous frame is left, neither its colours nor their lookups
+        let palette = builder.build(&blue, 256);
+        assert_eq!(palette.colors(), [[0, 0, 100], [0, 0, 200]]);
+        let red = palette.index([200, 0, 0]);
+        assert_eq!(palette.colors()[red as usize], [0, 0, 100]);
+    }
+}
@@ -0,0 +1,104 @@
+use scrap::Display;
+use crate::capturer::{CaptureError, Frame, FrameSource, PixelFormat, ScreenSource};
+
+/// Places the displays, given their sizes in the order of `Display::all`. A display takes its
+/// offset from `offsets`; those without one are put in a row, right of the previous display.
+/// The offsets are the top left corners in desktop coordinates and may be negative, e.g. for a
+/// display on the left of the primary one.
+pub fn arrange(sizes: &[(u32, u32)], offsets: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let mut arranged: Vec<(i32, i32)> = Vec::with_capacity(sizes.len());
+    for i in 0..sizes.len() {
+        let offset = offsets.get(i).copied().unwrap_or_else(|| match i {
+            0 => (0, 0),
+            _ => (arranged[i - 1].0 + sizes[i - 1].0 as i32, arranged[i - 1].1),
+        });
+        arranged.push(offset);
+    }
+    arranged
+}
+
+/// Top left corner and size of the smallest rectangle holding every display.
+pub fn bounds(sizes: &[(u32, u32)], offsets: &[(i32, i32)]) -> (i32, i32, u32, u32) {
+    let corners = sizes.iter().zip(offsets);
+    let left = corners.clone().map(|(_, o)| o.0).min().unwrap_or(0);
+    let top = corners.clone().map(|(_, o)| o.1).min().unwrap_or(0);
+    let right = corners.clone().map(|(s, o)| o.0 + s.0 as i32).max().unwrap_or(0);
+    let bottom = corners.map(|(s, o)| o.1 + s.1 as i32).max().unwrap_or(0);
+    (left, top, (right - left) as u32, (bottom - top) as u32)
+}
+
+/// Sizes of the displays connected now.
+pub fn display_sizes() -> Result<Vec<(u32, u32)>, CaptureError> {
+    let displays = Display::all().map_err(|_| CaptureError::NoDisplays)?;
+    Ok(displays.iter().map(|d| (d.width() as u32, d.height() as u32)).collect())
+}
+
+/// The whole desktop: every display drawn at its position in a single BGRA frame, so that an
+/// area can cross the edges between them. The space no display covers stays black.
+pub struct DesktopSource {
+    screens: Vec<(ScreenSource, u32, u32)>, // with their top left corner in the frame
+    frame: Frame, // kept, as a display without a new frame leaves its part as it was
+}
+
+impl DesktopSource {
+    /// `offsets` are the positions of the displays, see `arrange`.
+    pub fn open(offsets: &[(i32, i32)]) -> Result<Self, CaptureError> {
+        let sizes = display_sizes()?;
+        if sizes.is_empty() {
+            return Err(CaptureError::NoDisplays);
+        }
+        let offsets = arrange(&sizes, offsets);
+        let (left, top, width, height) = bounds(&sizes, &offsets);
+        let mut screens = Vec::with_capacity(sizes.len());
+        for (i, (x, y)) in offsets.into_iter().enumerate() {
+            screens.push((ScreenSource::open(i as u32)?, (x - left) as u32, (y - top) as u32));
+        }
+        let frame = Frame::new(width, height, PixelFormat::Bgra, vec![0; (width * height * 4) as usize]);
+        Ok(Self { screens, frame })
+    }
+}
+
+impl FrameSource for DesktopSource {
+    fn width(&self) -> u32 {
+        self.frame.w
+    }
+    fn height(&self) -> u32 {
+        self.frame.h
+    }
+    fn frame(&mut self) -> Result<&Frame, CaptureError> {
+        for (screen, x, y) in self.screens.iter_mut() {
+            let frame = match screen.frame() {
+                Ok(frame) => frame,
+                Err(CaptureError::Stalled { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            debug_assert_eq!(frame.format, self.frame.format);
+            let w = frame.w.min(self.frame.w - *x);
+            for row in 0..frame.h.min(self.frame.h - *y) {
+                let start = ((*y + row) * self.frame.stride + *x * 4) as usize;
+                self.frame.data[start..start + (w * 4) as usize].copy_from_slice(&frame.row(row)[..(w * 4) as usize]);
+            }
+        }
+        Ok(&self.frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displays_default_to_a_row() {
+        let sizes = [(1920, 1080), (1280, 1024), (800, 600)];
+        assert_eq!(arrange(&sizes, &[]), [(0, 0), (1920, 0), (3200, 0)]);
+        assert_eq!(bounds(&sizes, &arrange(&sizes, &[])), (0, 0, 4000, 1080));
+    }
+
+    #[test]
+    fn offsets_may_be_negative() {
+        // the second display is on the left of the primary one, a bit lower
+        let sizes = [(1920, 1080), (1280, 1024)];
+        let offsets = arrange(&sizes, &[(0, 0), (-1280, 200)]);
+        assert_eq!(bounds(&sizes, &offsets), (-1280, 0, 3200, 1224));
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+use device_query::{DeviceQuery, DeviceState};
+use crate::capturer::{Area, SourceKind};
+use crate::desktop;
+
+// time the area takes to cover about two thirds of the way to the pointer
+const SMOOTHING: Duration = Duration::from_millis(150);
+
+/// Pans an area of fixed size so that the pointer stays at its centre, easing the motion so that
+/// the cast does not jerk with every twitch of the mouse. The area never leaves the source.
+pub struct Follower {
+    device_state: DeviceState,
+    origin: (i32, i32),   // of the source, in screen coordinates
+    position: (f32, f32), // of the area, before rounding
+    last: Instant,
+}
+
+impl Follower {
+    /// Follows the pointer over a source whose top left corner is at `origin` on the screen.
+    /// Returns `None` if the pointer cannot be read, e.g. without a display server.
+    pub fn new(origin: (i32, i32), area: &Area) -> Option<Self> {
+        let device_state = DeviceState::checked_new()?;
+        Some(Self { device_state, origin, position: (area.x as f32, area.y as f32), last: Instant::now() })
+    }
+    /// Moves `area` towards the pointer, within a `source_width`x`source_height` source.
+    pub fn update(&mut self, area: &mut Area, source_width: u32, source_height: u32) {
+        let (x, y) = self.device_state.get_mouse().coords;
+        let pointer = (x - self.origin.0, y - self.origin.1);
+        let elapsed = self.last.elapsed();
+        self.last = Instant::now();
+        self.position = step(self.position, pointer, (area.width, area.height), (source_width, source_height), elapsed);
+        (area.x, area.y) = (self.position.0.round() as u32, self.position.1.round() as u32);
+    }
+}
+
+// where an area of `size` at `position` goes after `elapsed` on its way to centre `pointer`
+fn step(position: (f32, f32), pointer: (i32, i32), size: (u32, u32), source: (u32, u32), elapsed: Duration) -> (f32, f32) {
+    // exponential easing, the same whatever the frame rate
+    let t = 1.0 - (-elapsed.as_secs_f32() / SMOOTHING.as_secs_f32()).exp();
+    let axis = |position: f32, pointer: i32, size: u32, source: u32| {
+        let max = source.saturating_sub(size) as f32;
+        let target = (pointer as f32 - size as f32 / 2.0).clamp(0.0, max);
+        (position + (target - position) * t).clamp(0.0, max)
+    };
+    (axis(position.0, pointer.0, size.0, source.0), axis(position.1, pointer.1, size.1, source.1))
+}
+
+/// Where the pointer is now on a source whose top left corner is at `origin` on the screen.
+pub fn pointer(origin: (i32, i32)) -> Option<(i32, i32)> {
+    let (x, y) = DeviceState::checked_new()?.get_mouse().coords;
+    Some((x - origin.0, y - origin.1))
+}
+
+/// Where the top left corner of the source is in screen coordinates, for the sources the pointer
+/// moves over. `display_offsets` places the displays, see `desktop::arrange`.
+pub fn source_origin(source_kind: &SourceKind, display: u32, display_offsets: &[(i32, i32)]) -> Option<(i32, i32)> {
+    let sizes = desktop::display_sizes().ok()?;
+    match source_kind {
+        SourceKind::Screen => desktop::arrange(&sizes, display_offsets).get(display as usize).copied(),
+        SourceKind::Desktop { offsets } => {
+            let (left, top, _, _) = desktop::bounds(&sizes, &desktop::arrange(&sizes, offsets));
+            Some((left, top))
+        }
+        SourceKind::TestPattern { .. } | SourceKind::Replay { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn area_eases_towards_the_pointer() {
+        let source = (3840, 2160);
+        let size = (1280, 720);
+        // long after, the pointer is at the centre
+        let position = step((0.0, 0.0), (2000, 1000), size, source, Duration::from_secs(10));
+        assert_eq!(position, (1360.0, 640.0));
+        // a frame later, only part of the way
+        let position = step((0.0, 0.0), (2000, 1000), size, source, Duration::from_millis(33));
+        assert!(position.0 > 0.0 && position.0 < 1360.0 / 2.0);
+    }
+
+    #[test]
+    fn area_stays_within_the_source() {
+        let (source, size, long) = ((1920, 1080), (1280, 720), Duration::from_secs(10));
+        assert_eq!(step((300.0, 300.0), (10, -50), size, source, long), (0.0, 0.0));
+        assert_eq!(step((300.0, 300.0), (1910, 5000), size, source, long), (640.0, 360.0));
+        // an area as big as the source does not move
+        assert_eq!(step((0.0, 0.0), (1000, 500), source, source, long), (0.0, 0.0));
+    }
+}
@@ -1,5 +1,19 @@
-use crate::capturer::{Area, Frame};
-use crate::util::Message;
+use crate::bandwidth::OperatingPoint;
+use crate::capturer::{Area, Frame, FrameSource, OutputSize, PixelFormat, SourceKind};
+use crate::clip::{self, GifOptions};
+use crate::depth::ColorDepth;
+use crate::desktop;
+use crate::mask::{Mask, MaskPreset, MaskStyle};
+use crate::overlay::{Corner, OverlayOptions};
+use crate::recorder::{RecordSettings, VideoFormat};
+use crate::replay::ReplaySource;
+use crate::stats::{StatsHistory, HISTORY_LEN, METRICS};
+use crate::tracks::{RecordMode, TrackLayout};
+use crate::transfer::{FileOffer, TransferState, TransferStatus};
+use crate::util::{Event, Message, MAX_TRACKS};
+use crate::zoom::{ZoomRequest, ZoomTarget, MAX_FACTOR};
+use crate::receiver::GuiSink;
+use crate::sender::CastOptions;
 use crate::{receiver, sender};
 use device_query::{DeviceQuery, DeviceState};
 use eframe::egui::load::SizedTexture;
@@ -9,11 +23,12 @@ use eframe::egui::{
 };
 use eframe::{egui, emath};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
 use std::thread::JoinHandle;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use std::{mem, thread};
 use scrap::Display;
 
@@ -24,6 +39,14 @@ const SECT_RECEIVE: &str = "Receive";
 const SECT_HOTKEY: &str = "Hotkey";
 const SECT_ANNOTATION: &str = "Annotation";
 const SECT_QUIT: &str = "Quit";
+const SECT_MASKS: &str = "Masks";
+const SECT_ZOOM: &str = "Zoom";
+const SECT_RECORDING: &str = "Recording";
+
+const DEFAULT_DOWNLOAD_DIR: &str = "./downloads";
+
+// frame size as sent, and as captured before scaling
+type Resolution = ((u32, u32), (u32, u32));
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 enum State {
@@ -35,16 +58,23 @@ enum State {
     Receiving,
     Hotkey,
     Annotation,
+    Recording,
 }
 #[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
 struct Backup {
     pub ip_addr: String,
     hotkeys: HashMap<String, String>,
-}
-impl Backup {
-    fn new(ip_addr: String, hotkeys: HashMap<String, String>) -> Self {
-        Self { ip_addr, hotkeys }
-    }
+    download_dir: String,
+    bandwidth_cap: u32,
+    display_offsets: Vec<(i32, i32)>,
+    output_size: OutputSize,
+    color_depth: ColorDepth,
+    mask_presets: Vec<MaskPreset>,
+    overlay_enabled: bool,
+    overlay: OverlayOptions,
+    record_settings: RecordSettings,
+    gif_options: GifOptions,
 }
 #[derive(Default)]
 pub struct EframeApp {
@@ -61,25 +91,85 @@ pub struct EframeApp {
     hotkeys: HashMap<String, String>,
 
     // selection options support
+    source_kind: SourceKind,
+    source_size: (u32, u32), // of sources other than the screen
+    source_error: Option<String>,
+    replay_path: String,
+    playback: Option<(Duration, Duration)>,
     displays: Vec<Display>,
+    display_offsets: Vec<(i32, i32)>, // where the displays are on the desktop, see desktop::arrange
+    desktop_origin: (i32, i32), // top left corner of the desktop frame, in screen coordinates
     //selected_display: u32,
     area: Area,
     screen_width_max: u32,
     screen_height_max: u32,
+    area_error: Option<String>,
+    tracks: Vec<Area>, // cast as separate tracks; when empty, `area` alone is cast
+    follow_mouse: bool, // the sender pans the areas after the pointer
     sel_opt_modify: bool,
     drag_state: DragState,
     modify_by_drag: bool,
 
+    // privacy masks support
+    masks: Vec<Mask>,
+    masks_enabled: bool, // toggled by hotkey
+    mask_style: MaskStyle, // of the next mask drawn
+    mask_presets: Vec<MaskPreset>,
+    preset_name: String,
+    drawing_mask: bool, // the drag in progress draws a mask rather than the area
+
+    // watermark support
+    overlay_enabled: bool,
+    overlay: OverlayOptions,
+    logo_path: String, // empty for no logo
+
+    // zoom support
+    zoomed: bool, // as reported by the sender
+    zoom_factor: f32,
+    zoom_target: ZoomTarget,
+
+    // tracks support, receiver side
+    track_count: u32,
+    track_layout: TrackLayout,
+    record_mode: RecordMode,
+
+    // bandwidth support
+    bandwidth_cap: u32, // Mbit/s, 0 means no cap
+    operating_point: Option<OperatingPoint>,
+    output_size: OutputSize,
+    color_depth: ColorDepth,
+    resolutions: BTreeMap<u32, Resolution>, // of each track received
+
     // annotation tool support
     lines: Vec<Vec<Pos2>>,
     stroke: Stroke,
 
+    // statistics support
+    stats: StatsHistory,
+
+    // file transfer support
+    download_dir: String,
+    file_path: String,
+    transfers: BTreeMap<(bool, u32), TransferStatus>,
+    file_offers: Vec<FileOffer>,
+
     // utils to manage stream of frames
     texture_handle: Option<TextureHandle>,
     frame_r: Option<Receiver<Frame>>, // for receiver mode only!
     msg_s: Option<Sender<Message>>,
+    event_r: Option<Receiver<Event>>,
     join_handle: Option<JoinHandle<()>>,
     save_option: bool,
+    record_settings: RecordSettings,
+    record_directory: String,
+
+    // gif clips support, receiver side
+    clip_in: Option<Instant>,
+    clip_out: Option<Instant>,
+    clip_last: u32, // s
+    gif_options: GifOptions,
+    gif_exporting: bool, // until the receiver tells how the export went
+    gif_status: Option<String>,
 }
 
 #[derive(Default)]
@@ -110,6 +200,11 @@ impl EframeApp {
             displays: ds,
             area: Area::new(0, 0, width as u32, height as u32, 0),
             local_ip_addr: local_ip_address::local_ip().unwrap().to_string(),
+            download_dir: DEFAULT_DOWNLOAD_DIR.to_string(),
+            record_directory: RecordSettings::default().directory.display().to_string(),
+            masks_enabled: true,
+            zoom_factor: 2.0,
+            clip_last: 10,
             ..Default::default()
         };
 
@@ -117,6 +212,23 @@ impl EframeApp {
             let backup: Backup = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
             app.ip_addr = backup.ip_addr;
             app.hotkeys = backup.hotkeys;
+            if !backup.download_dir.is_empty() {
+                app.download_dir = backup.download_dir;
+            }
+            app.bandwidth_cap = backup.bandwidth_cap;
+            app.display_offsets = backup.display_offsets;
+            app.output_size = backup.output_size;
+            app.color_depth = backup.color_depth;
+            app.mask_presets = backup.mask_presets;
+            app.overlay_enabled = backup.overlay_enabled;
+            app.logo_path = backup.overlay.logo.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+            app.overlay = backup.overlay;
+            app.record_directory = backup.record_settings.directory.display().to_string();
+            app.record_settings = backup.record_settings;
+            app.gif_options = backup.gif_options;
+            // added after the first release, so older backups lack it
+            app.hotkeys.entry(SECT_MASKS.to_string()).or_default();
+            app.hotkeys.entry(SECT_ZOOM.to_string()).or_default();
         } else {
             app.hotkeys.insert(SECT_HOME.to_string(), "".to_string());
             app.hotkeys.insert(SECT_SEND.to_string(), "".to_string());
@@ -124,6 +236,8 @@ impl EframeApp {
             app.hotkeys
                 .insert(SECT_ANNOTATION.to_string(), "".to_string());
             app.hotkeys.insert(SECT_QUIT.to_string(), "".to_string());
+            app.hotkeys.insert(SECT_MASKS.to_string(), "".to_string());
+            app.hotkeys.insert(SECT_ZOOM.to_string(), "".to_string());
         }
 
         app
@@ -138,13 +252,21 @@ impl EframeApp {
                 .spacing(Vec2::new(15.0, 15.0))
                 .max_col_width(200.0)
                 .show(ui, |ui| {
-                    if self.displays.len() == 2 {
-                        ui.radio_value(&mut self.area.selected_display, 0, "Primary");
-                        ui.radio_value(&mut self.area.selected_display, 1, "Secondary");
+                    if self.source_kind != SourceKind::Screen {
+                        (self.screen_width_max, self.screen_height_max) = self.source_size;
+                    } else if self.displays.len() > 1 {
+                        ui.horizontal(|ui| {
+                            for (i, d) in self.displays.iter().enumerate() {
+                                let name = if i == 0 { "Primary".to_string() } else { format!("Display {}", i + 1) };
+                                ui.radio_value(&mut self.area.selected_display, i as u32, format!("{name} ({}x{})", d.width(), d.height()));
+                            }
+                        });
                         ui.end_row();
 
-                        self.screen_width_max = self.displays[self.area.selected_display as usize].width() as u32;
-                        self.screen_height_max = self.displays[self.area.selected_display as usize].height() as u32;
+                        if let Some(d) = self.displays.get(self.area.selected_display as usize) {
+                            self.screen_width_max = d.width() as u32;
+                            self.screen_height_max = d.height() as u32;
+                        }
                     }
                     ui.label("Insert Receiver's IP address: ");
                     ui.text_edit_singleline(&mut self.ip_addr);
@@ -195,14 +317,372 @@ impl EframeApp {
                         self.modify_by_drag = !self.modify_by_drag;
                     }
                 });
+            if let Some(e) = &self.area_error {
+                ui.colored_label(Color32::RED, e);
+            }
             ctx.request_repaint();
         });
 
         self.update_drag_state();
     }
+    /// Fits the area to the selected display or source, telling the user when it cannot be cast.
+    fn check_area(&mut self) -> bool {
+        match self.area.clamped(self.screen_width_max, self.screen_height_max) {
+            Ok(area) => {
+                self.area = area;
+                self.area_error = None;
+                true
+            }
+            Err(e) => {
+                self.area_error = Some(format!("Invalid area: {e}"));
+                false
+            }
+        }
+    }
+    /// The areas to cast, one for each track.
+    fn cast_areas(&self) -> Vec<Area> {
+        if self.tracks.is_empty() {
+            vec![self.area.clone()]
+        } else {
+            self.tracks.clone()
+        }
+    }
+    fn track_options(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.label("Tracks: each one is cast as a separate stream. Without tracks, the area above is cast.");
+            let mut removed = None;
+            for (i, area) in self.tracks.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "Track {i}: display {}, {}x{} at ({}, {})",
+                        area.selected_display, area.width, area.height, area.x, area.y
+                    ));
+                    if ui.button("Remove").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = removed {
+                self.tracks.remove(i);
+            }
+            let add = ui.add_enabled(self.tracks.len() < MAX_TRACKS as usize, egui::Button::new("Add the area above as a track"));
+            if add.on_disabled_hover_text(format!("A cast has at most {MAX_TRACKS} tracks.")).clicked() && self.check_area() {
+                self.tracks.push(self.area.clone());
+            }
+            ui.checkbox(&mut self.follow_mouse, "Follow the mouse")
+                .on_hover_text("Each area keeps its size and pans to keep the pointer at its centre, within its display.");
+        });
+    }
+    fn track_view_options(&mut self, ui: &mut Ui) {
+        let (layout, record_mode) = (self.track_layout, self.record_mode);
+        ui.horizontal(|ui| {
+            ui.label("Show:");
+            ui.radio_value(&mut self.track_layout, TrackLayout::SideBySide, "All tracks side by side");
+            for t in 0..self.track_count.max(1) {
+                ui.radio_value(&mut self.track_layout, TrackLayout::Single(t), format!("Track {t}"));
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Save:");
+            ui.radio_value(&mut self.record_mode, RecordMode::Composited, "One video with the tracks side by side");
+            ui.radio_value(&mut self.record_mode, RecordMode::Separate, "One video for each track");
+        });
+        if (layout, record_mode) != (self.track_layout, self.record_mode) {
+            if let Some(s) = self.msg_s.as_mut() {
+                if let Err(e) = s.send(Message::tracks_request(self.track_layout, self.record_mode)) {
+                    println!("Impossible sending tracks request: {e}");
+                }
+            }
+        }
+    }
+    fn mask_options(&mut self, ui: &mut Ui) {
+        let mut changed = false;
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                changed |= ui
+                    .checkbox(&mut self.masks_enabled, "Privacy masks")
+                    .on_hover_text("Regions blacked out or pixelated in every frame sent or recorded. The Masks hotkey toggles them.")
+                    .changed();
+                ui.radio_value(&mut self.mask_style, MaskStyle::Solid, "Solid");
+                ui.radio_value(&mut self.mask_style, MaskStyle::Pixelate, "Pixelated");
+                if ui.button("Draw a mask by drag").clicked() {
+                    self.drawing_mask = true;
+                    self.modify_by_drag = true;
+                }
+            });
+            let mut removed = None;
+            for (i, mask) in self.masks.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let area = &mask.area;
+                    ui.label(format!(
+                        "Mask {i} ({:?}): display {}, {}x{} at ({}, {})",
+                        mask.style, area.selected_display, area.width, area.height, area.x, area.y
+                    ));
+                    if ui.button("Remove").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = removed {
+                self.masks.remove(i);
+                changed = true;
+            }
+            ui.horizontal(|ui| {
+                ui.label("Preset name:");
+                ui.text_edit_singleline(&mut self.preset_name);
+                if ui.button("Save the masks").clicked() && !self.preset_name.is_empty() {
+                    let preset = MaskPreset { name: self.preset_name.clone(), masks: self.masks.clone() };
+                    match self.mask_presets.iter_mut().find(|p| p.name == preset.name) {
+                        Some(p) => *p = preset,
+                        None => self.mask_presets.push(preset),
+                    }
+                }
+            });
+            let (mut loaded, mut deleted) = (None, None);
+            for (i, preset) in self.mask_presets.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} ({} masks)", preset.name, preset.masks.len()));
+                    if ui.button("Load").clicked() {
+                        loaded = Some(i);
+                    }
+                    if ui.button("Delete").clicked() {
+                        deleted = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = loaded {
+                self.masks = self.mask_presets[i].masks.clone();
+                self.preset_name = self.mask_presets[i].name.clone();
+                changed = true;
+            }
+            if let Some(i) = deleted {
+                self.mask_presets.remove(i);
+            }
+        });
+        if changed {
+            self.send_masks();
+        }
+    }
+    fn overlay_options(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.checkbox(&mut self.overlay_enabled, "Watermark")
+                .on_hover_text("Burns who is casting and when into every frame sent or recorded.");
+            if !self.overlay_enabled {
+                return;
+            }
+            egui::Grid::new("overlay_grid")
+                .spacing(Vec2::new(15.0, 5.0))
+                .show(ui, |ui| {
+                    ui.label("Caster name:");
+                    ui.text_edit_singleline(&mut self.overlay.caster);
+                    ui.end_row();
+                    ui.label("Show:");
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.overlay.time, "Date and time (UTC)");
+                        ui.checkbox(&mut self.overlay.frame_number, "Frame number");
+                    });
+                    ui.end_row();
+                    ui.label("Logo image:");
+                    ui.text_edit_singleline(&mut self.logo_path);
+                    ui.end_row();
+                    ui.label("Corner:");
+                    egui::ComboBox::from_id_salt("overlay_corner")
+                        .selected_text(self.overlay.corner.name())
+                        .show_ui(ui, |ui| {
+                            for (corner, name) in Corner::ALL {
+                                ui.selectable_value(&mut self.overlay.corner, corner, name);
+                            }
+                        });
+                    ui.end_row();
+                    ui.label("Opacity:");
+                    ui.add(egui::Slider::new(&mut self.overlay.opacity, 0.1..=1.0));
+                    ui.end_row();
+                });
+            self.overlay.logo = Some(PathBuf::from(&self.logo_path)).filter(|_| !self.logo_path.is_empty());
+        });
+    }
+    /// The masks to apply, none when they are toggled off.
+    fn active_masks(&self) -> Vec<Mask> {
+        if self.masks_enabled {
+            self.masks.clone()
+        } else {
+            Vec::new()
+        }
+    }
+    fn toggle_masks(&mut self) {
+        self.masks_enabled = !self.masks_enabled;
+        self.send_masks();
+    }
+    /// Updates the masks of the cast in progress, if any.
+    fn send_masks(&mut self) {
+        if !matches!(self.state, State::Sending) {
+            return;
+        }
+        let masks = self.active_masks();
+        if let Some(s) = self.msg_s.as_mut() {
+            if let Err(e) = s.send(Message::masks_request(masks)) {
+                println!("Impossible sending masks request: {e}");
+            }
+        }
+    }
+    fn zoom_options(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.label("Zoom:");
+                ui.add(egui::Slider::new(&mut self.zoom_factor, 1.0..=MAX_FACTOR).suffix("x"));
+                let is_pointer = self.zoom_target == ZoomTarget::Pointer;
+                if ui.radio(is_pointer, "Around the pointer").clicked() {
+                    self.zoom_target = ZoomTarget::Pointer;
+                }
+                if ui.radio(!is_pointer, "Around a point").clicked() && is_pointer {
+                    self.zoom_target = ZoomTarget::Point(self.area.x + self.area.width / 2, self.area.y + self.area.height / 2);
+                }
+                if let ZoomTarget::Point(x, y) = &mut self.zoom_target {
+                    ui.label("x");
+                    ui.add(egui::DragValue::new(x).speed(10).range(0..=self.screen_width_max));
+                    ui.label("y");
+                    ui.add(egui::DragValue::new(y).speed(10).range(0..=self.screen_height_max));
+                }
+                let label = if self.zoomed { "Zoom out" } else { "Zoom in" };
+                if ui.button(label).on_hover_text("The Zoom hotkey does the same.").clicked() {
+                    self.toggle_zoom();
+                }
+            });
+        });
+    }
+    /// Zooms the cast in progress in or out; the frames keep their size. The sender tells whether
+    /// it zoomed in, which it does not when the target is outside the area.
+    fn toggle_zoom(&mut self) {
+        if !matches!(self.state, State::Sending) {
+            return;
+        }
+        let request = ZoomRequest { factor: self.zoom_factor, target: self.zoom_target };
+        if let Some(s) = self.msg_s.as_mut() {
+            if let Err(e) = s.send(Message::zoom_request((!self.zoomed).then_some(request))) {
+                println!("Impossible sending zoom request: {e}");
+            }
+        }
+    }
+    fn source_options(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Source:");
+            let is_screen = self.source_kind == SourceKind::Screen;
+            if ui.radio(is_screen, "Screen").clicked() && !is_screen {
+                // the list is empty when the displays were unplugged or could not be listed
+                match self.displays.get(self.area.selected_display as usize) {
+                    Some(display) => {
+                        self.area = Area::new(0, 0, display.width() as u32, display.height() as u32, self.area.selected_display);
+                        self.source_kind = SourceKind::Screen;
+                        self.source_error = None;
+                    }
+                    None => {
+                        self.source_error.get_or_insert_with(|| "No display found to capture".to_string());
+                    }
+                }
+            }
+            let is_desktop = matches!(self.source_kind, SourceKind::Desktop { .. });
+            if ui.radio(is_desktop, "Whole desktop").clicked() && !is_desktop {
+                self.source_error = None;
+                self.set_desktop();
+            }
+            let is_pattern = matches!(self.source_kind, SourceKind::TestPattern { .. });
+            if ui.radio(is_pattern, "Test pattern").clicked() && !is_pattern {
+                self.source_kind = SourceKind::TestPattern { width: 1920, height: 1080 };
+                self.source_error = None;
+                self.set_source_size(1920, 1080);
+            }
+            let is_replay = matches!(self.source_kind, SourceKind::Replay { .. });
+            if ui.radio(is_replay, "Replay").clicked() && !is_replay {
+                self.source_kind = SourceKind::Replay { path: PathBuf::from(&self.replay_path), looping: true };
+                self.load_replay();
+            }
+        });
+        let mut load = false;
+        match &mut self.source_kind {
+            SourceKind::Screen => {}
+            SourceKind::Desktop { .. } => {
+                // scrap does not tell where the displays are, so the user places them
+                let mut changed = false;
+                ui.label("Position of each display on the desktop, in pixels:");
+                for (i, d) in self.displays.iter().enumerate() {
+                    let Some((x, y)) = self.display_offsets.get_mut(i) else { continue; };
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Display {} ({}x{}): x", i + 1, d.width(), d.height()));
+                        changed |= ui.add(egui::DragValue::new(x).speed(10)).changed();
+                        ui.label("y");
+                        changed |= ui.add(egui::DragValue::new(y).speed(10)).changed();
+                    });
+                }
+                if changed {
+                    self.set_desktop();
+                }
+            }
+            SourceKind::TestPattern { width, height } => {
+                let (mut w, mut h) = (*width, *height);
+                ui.horizontal(|ui| {
+                    ui.label("Resolution:");
+                    let w_changed = ui.add(egui::DragValue::new(width).speed(10).range(16..=7680)).changed();
+                    ui.label("x");
+                    let h_changed = ui.add(egui::DragValue::new(height).speed(10).range(16..=4320)).changed();
+                    if w_changed || h_changed {
+                        (w, h) = (*width, *height);
+                        load = true;
+                    }
+                });
+                if load {
+                    self.set_source_size(w, h);
+                    return;
+                }
+            }
+            SourceKind::Replay { looping, .. } => {
+                ui.horizontal(|ui| {
+                    ui.label("Session file, AVI video or image sequence (e.g. ./shots/%d_img.jpeg):");
+                    ui.text_edit_singleline(&mut self.replay_path);
+                    load = ui.button("Load").clicked();
+                    ui.checkbox(looping, "Loop");
+                });
+            }
+        }
+        if load {
+            if let SourceKind::Replay { path, .. } = &mut self.source_kind {
+                *path = PathBuf::from(&self.replay_path);
+            }
+            self.load_replay();
+        }
+        if let Some(e) = &self.source_error {
+            ui.colored_label(Color32::RED, e);
+        }
+    }
+    /// Selects the whole desktop as the source, with the displays where the user placed them.
+    fn set_desktop(&mut self) {
+        let sizes: Vec<(u32, u32)> = self.displays.iter().map(|d| (d.width() as u32, d.height() as u32)).collect();
+        self.display_offsets = desktop::arrange(&sizes, &self.display_offsets);
+        let (left, top, width, height) = desktop::bounds(&sizes, &self.display_offsets);
+        self.desktop_origin = (left, top);
+        self.source_kind = SourceKind::Desktop { offsets: self.display_offsets.clone() };
+        self.set_source_size(width, height);
+    }
+    fn set_source_size(&mut self, width: u32, height: u32) {
+        self.source_size = (width, height);
+        self.area = Area::new(0, 0, width, height, self.area.selected_display);
+    }
+    fn load_replay(&mut self) {
+        // opening the replay tells its resolution, so that the area can be selected
+        match ReplaySource::open(Path::new(&self.replay_path), false) {
+            Ok(replay) => {
+                self.source_error = None;
+                self.set_source_size(replay.width(), replay.height());
+            }
+            Err(e) => self.source_error = Some(format!("Impossible opening {}: {e}", self.replay_path)),
+        }
+    }
     fn update_drag_state(&mut self) {
         let device_state = DeviceState::new();
-        let mouse = device_state.get_mouse();
+        let mut mouse = device_state.get_mouse();
+        // the desktop frame starts at the top left corner of the leftmost and topmost displays
+        if matches!(self.source_kind, SourceKind::Desktop { .. }) {
+            mouse.coords = ((mouse.coords.0 - self.desktop_origin.0).max(0), (mouse.coords.1 - self.desktop_origin.1).max(0));
+        }
 
         if self.modify_by_drag {
             if *mouse.button_pressed.get(1).expect("button not found") {
@@ -218,12 +698,22 @@ impl EframeApp {
                 if let (Some(coords_start), Some(coords_end)) =
                     (self.drag_state.start, self.drag_state.end)
                 {
-                    self.area.x = std::cmp::min(coords_start.0, coords_end.0) as u32;
-                    self.area.y = std::cmp::min(coords_start.1, coords_end.1) as u32;
-                    self.area.width = (coords_end.0 - coords_start.0).abs() as u32;
-                    self.area.height = (coords_end.1 - coords_start.1).abs() as u32;
+                    let area = Area::new(
+                        std::cmp::min(coords_start.0, coords_end.0) as u32,
+                        std::cmp::min(coords_start.1, coords_end.1) as u32,
+                        (coords_end.0 - coords_start.0).abs() as u32,
+                        (coords_end.1 - coords_start.1).abs() as u32,
+                        self.area.selected_display,
+                    );
+                    if self.drawing_mask {
+                        self.masks.push(Mask { area, style: self.mask_style });
+                        self.send_masks();
+                    } else {
+                        self.area = area;
+                    }
                 }
                 self.modify_by_drag = false;
+                self.drawing_mask = false;
             }
         }
     }
@@ -260,6 +750,16 @@ impl EframeApp {
                         ui.end_row();
                     }
 
+                    if let Some(value) = self.hotkeys.get_mut(SECT_MASKS) {
+                        ui.label(SECT_MASKS);
+                        ui.text_edit_singleline(value);
+                        ui.end_row();
+                    }
+                    if let Some(value) = self.hotkeys.get_mut(SECT_ZOOM) {
+                        ui.label(SECT_ZOOM);
+                        ui.text_edit_singleline(value);
+                        ui.end_row();
+                    }
                     if let Some(value) = self.hotkeys.get_mut(&SECT_QUIT.to_string()) {
                         ui.label(SECT_QUIT);
                         ui.text_edit_singleline(value);
@@ -268,6 +768,47 @@ impl EframeApp {
                 });
         });
     }
+    fn recording_settings(&mut self, ui: &mut Ui) {
+        ui.heading("Recording");
+        ui.label("Where and how the receiver saves the casts.");
+        ui.add_space(10.0);
+        ui.group(|ui| {
+            egui::Grid::new("recording_grid")
+                .spacing(Vec2::new(15.0, 10.0))
+                .show(ui, |ui| {
+                    ui.label("Directory:");
+                    ui.text_edit_singleline(&mut self.record_directory);
+                    ui.end_row();
+                    ui.label("File name:");
+                    ui.text_edit_singleline(&mut self.record_settings.template)
+                        .on_hover_text("{session}, {caster} and {date} are replaced by the session name, the address of the sender and the UTC date and time.");
+                    ui.end_row();
+                    ui.label("Session name:");
+                    ui.text_edit_singleline(&mut self.record_settings.session);
+                    ui.end_row();
+                    ui.label("Format:");
+                    egui::ComboBox::from_id_salt("video_format")
+                        .selected_text(self.record_settings.format.name())
+                        .show_ui(ui, |ui| {
+                            for (format, name) in VideoFormat::ALL {
+                                ui.selectable_value(&mut self.record_settings.format, format, name);
+                            }
+                        });
+                    ui.end_row();
+                    ui.label("JPEG quality:");
+                    ui.add(egui::Slider::new(&mut self.record_settings.quality, 1..=100))
+                        .on_hover_text("Of the frames of AVI videos and image sequences, and of the AVI an MP4 is made from.");
+                    ui.end_row();
+                });
+            self.record_settings.directory = PathBuf::from(&self.record_directory);
+        });
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        ui.label(format!("For example: {}", self.record_settings.path("192.168.1.10", now, None).display()));
+        ui.add_space(10.0);
+        if ui.button("Back").clicked() {
+            self.go_back();
+        }
+    }
     fn annotation_tool(&mut self, ctx: &Context) {
         //initialization
         let layer_id = LayerId::background();
@@ -308,6 +849,298 @@ impl EframeApp {
             });
         painter.extend(shapes);
     }
+    fn playback_options(&mut self, ui: &mut Ui) {
+        let Some((position, duration)) = self.playback else { return; };
+        let mut seconds = position.as_secs_f32();
+        ui.horizontal(|ui| {
+            ui.label("Replay position:");
+            let slider = ui.add(egui::Slider::new(&mut seconds, 0.0..=duration.as_secs_f32()).suffix(" s"));
+            if slider.changed() {
+                self.playback = Some((Duration::from_secs_f32(seconds), duration));
+                if let Some(s) = self.msg_s.as_mut() {
+                    if let Err(e) = s.send(Message::seek_request(Duration::from_secs_f32(seconds))) {
+                        println!("Impossible sending seek request: {e}");
+                    }
+                }
+            }
+        });
+    }
+    fn output_size_options(&mut self, ui: &mut Ui) {
+        let output_size = self.output_size;
+        ui.horizontal(|ui| {
+            ui.label("Output size:");
+            egui::ComboBox::from_id_salt("output_size")
+                .selected_text(self.output_size.name())
+                .show_ui(ui, |ui| {
+                    for (size, name) in OutputSize::PRESETS {
+                        ui.selectable_value(&mut self.output_size, size, name);
+                    }
+                })
+                .response
+                .on_hover_text("Frames are scaled after the crop and never enlarged. Fit to bandwidth scales them only when the link is congested.");
+        });
+        if output_size != self.output_size {
+            if let Some(s) = self.msg_s.as_mut() {
+                if let Err(e) = s.send(Message::output_request(self.output_size)) {
+                    println!("Impossible sending output size request: {e}");
+                }
+            }
+        }
+    }
+    fn color_depth_options(&mut self, ui: &mut Ui) {
+        let color_depth = self.color_depth;
+        ui.horizontal(|ui| {
+            ui.label("Colours:");
+            egui::ComboBox::from_id_salt("color_depth")
+                .selected_text(self.color_depth.name())
+                .show_ui(ui, |ui| {
+                    for (depth, name) in ColorDepth::ALL {
+                        ui.selectable_value(&mut self.color_depth, depth, name);
+                    }
+                })
+                .response
+                .on_hover_text("Fewer colours make the frames several times lighter; text stays legible over poor links.");
+        });
+        if color_depth != self.color_depth {
+            if let Some(s) = self.msg_s.as_mut() {
+                if let Err(e) = s.send(Message::depth_request(self.color_depth)) {
+                    println!("Impossible sending colour depth request: {e}");
+                }
+            }
+        }
+    }
+    fn resolutions(&self, ui: &mut Ui) {
+        for (track, ((w, h), (capture_w, capture_h))) in &self.resolutions {
+            ui.label(format!("Track {track}: {w}x{h}, captured at {capture_w}x{capture_h}"));
+        }
+    }
+    fn bandwidth_options(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Bandwidth cap (Mbit/s):");
+            let response = ui.add(egui::DragValue::new(&mut self.bandwidth_cap).range(0..=10_000))
+                .on_hover_text("0 means no cap. Fps and scale are lowered automatically to stay under the cap.");
+            if response.changed() {
+                if let Some(s) = self.msg_s.as_mut() {
+                    if let Err(e) = s.send(Message::bandwidth_request(self.bandwidth_cap as u64 * 1_000_000)) {
+                        println!("Impossible sending bandwidth request: {e}");
+                    }
+                }
+            }
+        });
+        if let Some(point) = self.operating_point {
+            ui.label(format!(
+                "Streaming at {} fps, scale {:.0}%, {:.1} Mbit/s, socket blocked {:.0}% of the time",
+                point.fps,
+                point.scale * 100.0,
+                point.bitrate as f64 / 1_000_000.0,
+                point.backpressure * 100.0
+            ));
+        }
+    }
+    fn save_option_checkbox(&mut self, ui: &mut Ui, text: &str, hover_text: &str) {
+        let checkbox = ui
+            .checkbox(&mut self.save_option, text)
+            .on_hover_text(hover_text);
+        if checkbox.clicked() {
+            if let Some(s) = self.msg_s.as_mut() {
+                if let Err(e) = s.send(Message::save_request(self.save_option)) {
+                    println!("Impossible sending save_request: {e}");
+                }
+            }
+        }
+    }
+    fn gif_clip(&mut self, ui: &mut Ui) {
+        ui.collapsing("GIF clip", |ui| {
+            let idle = !self.gif_exporting;
+            egui::Grid::new("gif_clip_grid")
+                .spacing(Vec2::new(15.0, 10.0))
+                .show(ui, |ui| {
+                    ui.label("Fps:");
+                    ui.add(egui::DragValue::new(&mut self.gif_options.fps).range(1..=clip::MAX_FPS));
+                    ui.end_row();
+                    ui.label("Scale:");
+                    ui.add(egui::Slider::new(&mut self.gif_options.scale, 10..=100).suffix("%"));
+                    ui.end_row();
+                    ui.label("Last:");
+                    ui.add(egui::DragValue::new(&mut self.clip_last).range(1..=clip::MAX_CLIP_SECS).suffix(" s"));
+                    if ui.add_enabled(idle, egui::Button::new("Export")).clicked() {
+                        let now = Instant::now();
+                        let from = now.checked_sub(Duration::from_secs(self.clip_last as u64)).unwrap_or(now);
+                        self.export_gif(from, now);
+                    }
+                    ui.end_row();
+                    ui.horizontal(|ui| {
+                        if ui.button("Mark in").clicked() {
+                            (self.clip_in, self.clip_out) = (Some(Instant::now()), None);
+                        }
+                        if ui.add_enabled(self.clip_in.is_some(), egui::Button::new("Mark out")).clicked() {
+                            self.clip_out = Some(Instant::now());
+                        }
+                    });
+                    match (self.clip_in, self.clip_out) {
+                        (Some(from), Some(to)) => ui.label(format!("{:.1} s marked", (to - from).as_secs_f64())),
+                        (Some(from), None) => ui.label(format!("Marked in {:.0} s ago", from.elapsed().as_secs_f64())),
+                        _ => ui.label(""),
+                    };
+                    let marked = self.clip_in.zip(self.clip_out);
+                    if ui.add_enabled(idle && marked.is_some(), egui::Button::new("Export")).clicked() {
+                        let (from, to) = marked.unwrap();
+                        self.export_gif(from, to);
+                    }
+                    ui.end_row();
+                });
+            if !idle {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Exporting...");
+                });
+                // the receiver tells when it is done, which does not repaint by itself
+                ui.ctx().request_repaint_after(Duration::from_millis(200));
+            } else if let Some(status) = &self.gif_status {
+                ui.label(status);
+            }
+        });
+    }
+    /// Has the receiver export the frames it got from `from` to `to` as a GIF in the recording
+    /// directory, in the background.
+    fn export_gif(&mut self, from: Instant, to: Instant) {
+        let output = (1..)
+            .map(|n| self.record_settings.directory.join(format!("{}_clip{n}.gif", self.record_settings.session)))
+            .find(|path| !path.exists())
+            .unwrap();
+        if let Some(s) = self.msg_s.as_mut() {
+            match s.send(Message::clip_request(from, to, self.gif_options.clone(), output)) {
+                Ok(_) => (self.gif_exporting, self.gif_status) = (true, None),
+                Err(e) => println!("Impossible sending clip request: {e}"),
+            }
+        }
+    }
+    fn statistics(&self, ui: &mut Ui) {
+        ui.collapsing("Statistics", |ui| {
+            let Some(last) = self.stats.last() else {
+                ui.label("No statistics yet.");
+                return;
+            };
+            egui::Grid::new("statistics_grid")
+                .spacing(Vec2::new(15.0, 5.0))
+                .show(ui, |ui| {
+                    for (name, unit, metric) in METRICS {
+                        ui.label(format!("{name}: {:.1} {unit}", metric(last)));
+                        plot(ui, &self.stats.series(metric));
+                        ui.end_row();
+                    }
+                });
+        });
+    }
+    fn file_transfer(&mut self, ui: &mut Ui, ctx: &Context) {
+        ui.group(|ui| {
+            egui::Grid::new("file_transfer_grid")
+                .min_col_width(150.0)
+                .spacing(Vec2::new(15.0, 15.0))
+                .show(ui, |ui| {
+                    ui.label("Download directory:");
+                    ui.text_edit_singleline(&mut self.download_dir);
+                    ui.end_row();
+                    ui.label("File to send:");
+                    ui.text_edit_singleline(&mut self.file_path);
+                    if ui.button("Send file").clicked() && !self.file_path.is_empty() {
+                        if let Some(s) = self.msg_s.as_mut() {
+                            if let Err(e) = s.send(Message::send_file_request(PathBuf::from(&self.file_path))) {
+                                println!("Impossible sending file request: {e}");
+                            }
+                        }
+                    }
+                    ui.end_row();
+                });
+            for status in self.transfers.values() {
+                let direction = if status.outgoing { "Sending" } else { "Receiving" };
+                ui.horizontal(|ui| {
+                    ui.label(format!("{direction} {}", status.name));
+                    match &status.state {
+                        TransferState::Waiting => { ui.label("waiting for the peer..."); }
+                        TransferState::Active => {
+                            let progress = if status.total == 0 { 1.0 } else { status.done as f32 / status.total as f32 };
+                            ui.add(egui::ProgressBar::new(progress).show_percentage().desired_width(200.0));
+                        }
+                        TransferState::Completed(path) => { ui.label(format!("completed: {}", path.display())); }
+                        TransferState::Declined => { ui.label("declined"); }
+                        TransferState::Failed(e) => { ui.colored_label(Color32::RED, format!("failed: {e}")); }
+                    }
+                });
+            }
+        });
+
+        // accept or decline prompt
+        if let Some(offer) = self.file_offers.first().cloned() {
+            let mut reply = None;
+            egui::Window::new("Incoming file")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.label(format!("The peer wants to send you {} ({} bytes).", offer.name, offer.size));
+                    ui.horizontal(|ui| {
+                        if ui.button("Accept").clicked() {
+                            reply = Some(Message::accept_file_request(offer.id, PathBuf::from(&self.download_dir)));
+                        }
+                        if ui.button("Decline").clicked() {
+                            reply = Some(Message::decline_file_request(offer.id));
+                        }
+                    });
+                });
+            if let Some(msg) = reply {
+                self.file_offers.remove(0);
+                if let Some(s) = self.msg_s.as_mut() {
+                    if let Err(e) = s.send(msg) {
+                        println!("Impossible sending file reply: {e}");
+                    }
+                }
+            }
+        }
+    }
+    fn handle_events(&mut self) {
+        let events: Vec<Event> = self.event_r.as_ref().map(|r| r.try_iter().collect()).unwrap_or_default();
+        for event in events {
+            match event {
+                Event::FileOffered(offer) => self.file_offers.push(offer),
+                Event::Transfer(status) => {
+                    self.transfers.insert((status.outgoing, status.id), status);
+                }
+                Event::OperatingPoint(point) => self.operating_point = Some(point),
+                Event::Stats(sample) => self.stats.push(sample),
+                Event::Playback { position, duration } => self.playback = Some((position, duration)),
+                Event::SourceLost { track, error } => {
+                    // the displays may have changed: list them again and let the user pick one
+                    self.displays = Display::all().unwrap_or_default();
+                    if self.area.selected_display as usize >= self.displays.len() {
+                        self.area.selected_display = 0;
+                    }
+                    if matches!(self.source_kind, SourceKind::Desktop { .. }) {
+                        self.set_desktop();
+                    }
+                    self.source_error = Some(format!("Capture of track {track} stopped: {error}. Pick a source and apply it."));
+                    self.sel_opt_modify = true;
+                }
+                Event::AreaRejected { track, error, area } => {
+                    match self.tracks.get_mut(track as usize) {
+                        Some(a) => *a = area,
+                        None => self.area = area,
+                    }
+                    self.area_error = Some(format!("Area of track {track} rejected by the sender: {error}"));
+                }
+                Event::Tracks(count) => self.track_count = count,
+                Event::Zoom(zoomed) => self.zoomed = zoomed,
+                Event::Resolution { track, output, capture } => {
+                    self.resolutions.insert(track, (output, capture));
+                }
+                Event::ClipExported(result) => {
+                    self.gif_exporting = false;
+                    self.gif_status = Some(match result {
+                        Ok((path, frames)) => format!("Saved {frames} frames as {}", path.display()),
+                        Err(e) => format!("Impossible exporting the clip: {e}"),
+                    });
+                }
+            }
+        }
+    }
     fn show_alert(&mut self) {
         self.alert = true;
     }
@@ -331,6 +1164,14 @@ impl EframeApp {
             self.show_alert();
         }
     }
+    fn go_recording(&mut self) {
+        if self.check_if_streaming_is_finished() {
+            self.prev_state = self.state.clone();
+            self.state = State::Recording;
+        } else {
+            self.show_alert();
+        }
+    }
     fn go_annotation(&mut self) {
         self.prev_state = self.state.clone();
         self.state = State::Annotation
@@ -353,11 +1194,30 @@ impl EframeApp {
     }
     fn start_sending(&mut self) {
         let ip_addr = self.ip_addr.clone();
-        let area = self.area.clone();
+        let areas = self.cast_areas();
+        let source_kind = self.source_kind.clone();
+        self.zoomed = false;
+        let options = CastOptions {
+            bandwidth_cap: self.bandwidth_cap as u64 * 1_000_000,
+            output_size: self.output_size,
+            save_option: self.save_option,
+            masks: self.active_masks(),
+            overlay: Some(self.overlay.clone()).filter(|_| self.overlay_enabled),
+            follow_mouse: self.follow_mouse,
+            display_offsets: self.display_offsets.clone(),
+            color_depth: self.color_depth,
+        };
         let (s, r) = channel();
+        let (event_s, event_r) = channel();
         self.msg_s = Some(s);
-        let handle = thread::spawn(|| {
-            sender::start(ip_addr, area, r);
+        self.event_r = Some(event_r);
+        self.transfers.clear();
+        self.file_offers.clear();
+        self.operating_point = None;
+        self.playback = None;
+        self.stats.clear();
+        let handle = thread::spawn(move || {
+            sender::start(ip_addr, source_kind, areas, options, r, event_s);
         });
         self.join_handle = Some(handle);
         self.sel_opt_modify = false;
@@ -366,12 +1226,23 @@ impl EframeApp {
     fn start_receiving(&mut self, ctx: &Context) {
         let (msg_s, msg_r) = channel();
         let (frame_s, frame_r) = channel();
+        let (event_s, event_r) = channel();
         self.frame_r = Some(frame_r);
         self.msg_s = Some(msg_s);
-        let ctx_clone = ctx.clone();
+        self.event_r = Some(event_r);
+        self.transfers.clear();
+        self.file_offers.clear();
+        self.stats.clear();
+        self.track_count = 0;
+        self.resolutions.clear();
+        (self.clip_in, self.clip_out) = (None, None);
+        (self.gif_exporting, self.gif_status) = (false, None);
+        let sink = GuiSink::new(frame_s, ctx.clone());
         let save_option = self.save_option;
+        let (layout, record_mode) = (self.track_layout, self.record_mode);
+        let settings = self.record_settings.clone();
         let handle = thread::spawn(move || {
-            receiver::start(frame_s, msg_r, ctx_clone, save_option);
+            receiver::start(sink, msg_r, event_s, save_option, layout, record_mode, settings);
         });
         self.join_handle = Some(handle);
         self.state = State::Receiving;
@@ -401,6 +1272,8 @@ impl EframeApp {
 }
 impl eframe::App for EframeApp {
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
+        self.handle_events();
+
         // hotkey support
         for (action, shortcut) in self.hotkeys.clone().iter() {
             if !shortcut.is_empty() {
@@ -421,6 +1294,12 @@ impl eframe::App for EframeApp {
                         if action.contains(SECT_HOTKEY) {
                             self.go_hotkey();
                         }
+                        if action.contains(SECT_MASKS) {
+                            self.toggle_masks();
+                        }
+                        if action.contains(SECT_ZOOM) {
+                            self.toggle_zoom();
+                        }
                         if action.contains(SECT_QUIT) {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
@@ -453,6 +1332,9 @@ impl eframe::App for EframeApp {
                     if ui.button(SECT_HOTKEY).clicked() {
                         self.go_hotkey();
                     }
+                    if ui.button(SECT_RECORDING).clicked() {
+                        self.go_recording();
+                    }
                     if ui.button(SECT_ANNOTATION).clicked() {
                         self.go_annotation();
                     }
@@ -505,9 +1387,24 @@ impl eframe::App for EframeApp {
 
                         ui.heading("Sender!");
                         ui.add_space(10.0);
+                        self.source_options(ui);
+                        ui.add_space(10.0);
                         self.selection_options(ui, ctx);
                         ui.add_space(10.0);
-                        if ui.button("Start").clicked() {
+                        self.track_options(ui);
+                        ui.add_space(10.0);
+                        self.mask_options(ui);
+                        ui.add_space(10.0);
+                        self.overlay_options(ui);
+                        ui.add_space(10.0);
+                        self.output_size_options(ui);
+                        self.color_depth_options(ui);
+                        self.bandwidth_options(ui);
+                        ui.add_space(10.0);
+                        ui.checkbox(&mut self.save_option, "Record locally")
+                            .on_hover_text("If checked, the frames sent are also saved on this computer.");
+                        ui.add_space(10.0);
+                        if ui.button("Start").clicked() && self.check_area() {
                             self.start_sending();
                         }
                     }
@@ -516,6 +1413,13 @@ impl eframe::App for EframeApp {
                         ui.add_space(10.0);
                         ui.checkbox(&mut self.save_option, "Save streaming")
                             .on_hover_text("If checked, the stream will be saved.");
+                        ui.label(format!(
+                            "Saved as {} in {}, see Menu > {SECT_RECORDING}.",
+                            self.record_settings.format.name(),
+                            self.record_settings.directory.display()
+                        ));
+                        ui.add_space(10.0);
+                        self.track_view_options(ui);
                         ui.add_space(10.0);
                         if ui.button("Start").clicked() {
                             self.start_receiving(ctx);
@@ -523,14 +1427,19 @@ impl eframe::App for EframeApp {
                     }
                     State::Sending => {
                         ui.heading("Sending!");
+                        if let Some(e) = &self.source_error {
+                            ui.colored_label(Color32::RED, e);
+                        }
                         self.selection_options(ui, ctx);
                         if self.sel_opt_modify {
-                            if ui.button("Apply").clicked() {
+                            if ui.button("Apply").clicked() && self.check_area() {
+                                let areas = self.cast_areas();
                                 if let Some(s) = self.msg_s.as_mut() {
                                     println!("sending area request, display: {}", self.area.selected_display);
-                                    if let Ok(_) = s.send(Message::area_request(self.area.clone()))
+                                    if let Ok(_) = s.send(Message::area_request(areas))
                                     {
                                         self.sel_opt_modify = false;
+                                        self.source_error = None;
                                     } else {
                                         println!("couldn't send area request")
                                     }
@@ -541,9 +1450,25 @@ impl eframe::App for EframeApp {
                                 self.sel_opt_modify = true;
                             }
                         }
+                        if self.sel_opt_modify {
+                            self.track_options(ui);
+                        }
+                        self.mask_options(ui);
+                        self.zoom_options(ui);
                         if ui.button("Stop").clicked() {
                             self.stop_receiving_or_sending();
                         }
+                        ui.add_space(10.0);
+                        self.playback_options(ui);
+                        self.output_size_options(ui);
+                        self.color_depth_options(ui);
+                        self.bandwidth_options(ui);
+                        ui.add_space(10.0);
+                        self.save_option_checkbox(ui, "Record locally", "If checked, the frames sent are also saved on this computer.");
+                        ui.add_space(10.0);
+                        self.file_transfer(ui, ctx);
+                        ui.add_space(10.0);
+                        self.statistics(ui);
                         if self.check_if_streaming_is_finished() {
                             self.go_home();
                         }
@@ -551,25 +1476,24 @@ impl eframe::App for EframeApp {
                     State::Receiving => {
                         ui.heading(format!("Receiving on {}!", self.local_ip_addr));
                         ui.add_space(10.0);
-                        let checkbox = ui
-                            .checkbox(&mut self.save_option, "Save streaming")
-                            .on_hover_text("If checked, the stream will be saved.");
-                        if checkbox.clicked() {
-                            if let Some(s) = self.msg_s.as_mut() {
-                                if let Err(e) = s.send(Message::save_request(self.save_option)) {
-                                    println!("Impossible sending save_request: {e}");
-                                }
-                            }
-                        }
+                        self.save_option_checkbox(ui, "Save streaming", "If checked, the stream will be saved.");
+                        self.track_view_options(ui);
+                        self.resolutions(ui);
                         if ui.button("Stop").clicked() {
                             self.stop_receiving_or_sending();
                         }
+                        ui.add_space(10.0);
+                        self.file_transfer(ui, ctx);
+                        ui.add_space(10.0);
+                        self.gif_clip(ui);
+                        self.statistics(ui);
                         if self.check_if_streaming_is_finished() {
                             self.go_home();
                         }
                         //get new frame if available
                         if let Some(r) = &mut self.frame_r {
                             if let Ok(frame) = r.try_recv() {
+                                let frame = frame.into_format(PixelFormat::Rgb);
                                 if let Some(texture) = &mut self.texture_handle {
                                     texture.set(
                                         ColorImage::from_rgb(
@@ -578,13 +1502,6 @@ impl eframe::App for EframeApp {
                                         ),
                                         TextureOptions::default(),
                                     );
-                                    println!(
-                                        "Gui got frame {}",
-                                        SystemTime::now()
-                                            .duration_since(SystemTime::UNIX_EPOCH)
-                                            .unwrap()
-                                            .as_millis()
-                                    );
                                 }
                             }
                         }
@@ -602,6 +1519,9 @@ impl eframe::App for EframeApp {
                     State::Hotkey => {
                         self.hotkey_support(ui);
                     }
+                    State::Recording => {
+                        self.recording_settings(ui);
+                    }
                     State::Annotation => {
                         panic!("Annotation tool does not work with CentralPanel");
                     }
@@ -617,7 +1537,20 @@ impl eframe::App for EframeApp {
         });
     }
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        let backup = Backup::new(self.ip_addr.clone(), self.hotkeys.clone());
+        let backup = Backup {
+            ip_addr: self.ip_addr.clone(),
+            hotkeys: self.hotkeys.clone(),
+            download_dir: self.download_dir.clone(),
+            bandwidth_cap: self.bandwidth_cap,
+            display_offsets: self.display_offsets.clone(),
+            output_size: self.output_size,
+            color_depth: self.color_depth,
+            mask_presets: self.mask_presets.clone(),
+            overlay_enabled: self.overlay_enabled,
+            overlay: self.overlay.clone(),
+            record_settings: self.record_settings.clone(),
+            gif_options: self.gif_options.clone(),
+        };
 
         eframe::set_value(storage, eframe::APP_KEY, &backup);
     }
@@ -630,3 +1563,23 @@ impl eframe::App for EframeApp {
         // _visuals.window_fill() would also be a natural choice
     }
 }
+
+/// Draws the history of a metric as a line, scaled to its maximum.
+fn plot(ui: &mut Ui, values: &[f32]) {
+    let (response, painter) = ui.allocate_painter(Vec2::new(360.0, 40.0), Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+    let max = values.iter().cloned().fold(0.0, f32::max);
+    if values.len() < 2 || max <= 0.0 {
+        return;
+    }
+    let step = rect.width() / (HISTORY_LEN - 1) as f32;
+    let offset = HISTORY_LEN - values.len();
+    let points: Vec<Pos2> = values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| Pos2::new(rect.left() + (offset + i) as f32 * step, rect.bottom() - v / max * rect.height()))
+        .collect();
+    painter.add(egui::Shape::line(points, Stroke::new(1.5, ui.visuals().selection.bg_fill)));
+    response.on_hover_text(format!("max {max:.1}"));
+}
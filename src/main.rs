@@ -3,12 +3,41 @@ mod sender;
 mod receiver;
 mod util;
 mod capturer;
+mod bandwidth;
+mod stats;
+mod recorder;
+mod pipe;
+mod cli;
+mod transfer;
+mod pattern;
+mod replay;
+mod font;
+mod tracks;
+mod desktop;
+mod pool;
+mod mask;
+mod overlay;
+mod follow;
+mod zoom;
+mod depth;
+mod avi;
+mod clip;
 
 use std::default::Default;
 use eframe::egui::ViewportBuilder;
 use gui::EframeApp;
 
 fn main() {
+    // subcommands run headless, any other invocation opens the gui
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = cli::run(&args) {
+        if let Err(e) = result {
+            eprintln!("{e}\n{}", cli::USAGE);
+            std::process::exit(2);
+        }
+        return;
+    }
+
     let viewport = ViewportBuilder {
         transparent:Some(true),
         // maximized: Some(true),
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use crate::capturer::{Area, Frame};
+
+// side of the blocks of a pixelated mask, in source pixels
+const PIXEL_BLOCK: u32 = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MaskStyle {
+    /// Painted black.
+    #[default]
+    Solid,
+    /// Blurred into blocks, so that the layout stays readable but not the text.
+    Pixelate,
+}
+
+/// A region of a display redacted from every frame before it is sent or recorded. Its area is
+/// in source coordinates, like the area of a track, so that it stays over the same window
+/// whatever part of the display is cast.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Mask {
+    pub area: Area,
+    pub style: MaskStyle,
+}
+
+/// A named set of masks, e.g. one for each usual screen layout.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MaskPreset {
+    pub name: String,
+    pub masks: Vec<Mask>,
+}
+
+/// Redacts `frame`, which was cropped from `area` of its source, with the masks on the same
+/// display. Masks may stick out of the area: only what is inside is touched.
+pub fn apply(frame: &mut Frame, area: &Area, masks: &[Mask]) {
+    for mask in masks.iter().filter(|m| m.area.selected_display == area.selected_display) {
+        let m = &mask.area;
+        let left = m.x.max(area.x);
+        let top = m.y.max(area.y);
+        let right = (m.x + m.width).min(area.x + frame.w);
+        let bottom = (m.y + m.height).min(area.y + frame.h);
+        if left >= right || top >= bottom {
+            continue;
+        }
+        match mask.style {
+            MaskStyle::Solid => {
+                let pixel = frame.format.encode([0, 0, 0]);
+                fill(frame, (left - area.x, top - area.y, right - area.x, bottom - area.y), pixel);
+            }
+            MaskStyle::Pixelate => {
+                // blocks are aligned on the source, so that they do not shimmer when the area moves
+                let mut y = top - top % PIXEL_BLOCK;
+                while y < bottom {
+                    let mut x = left - left % PIXEL_BLOCK;
+                    while x < right {
+                        let block = (x.max(left) - area.x, y.max(top) - area.y, (x + PIXEL_BLOCK).min(right) - area.x, (y + PIXEL_BLOCK).min(bottom) - area.y);
+                        let pixel = average(frame, block);
+                        fill(frame, block, pixel);
+                        x += PIXEL_BLOCK;
+                    }
+                    y += PIXEL_BLOCK;
+                }
+            }
+        }
+    }
+}
+
+// rectangles are (left, top, right, bottom) in frame coordinates, right and bottom excluded
+fn fill(frame: &mut Frame, (left, top, right, bottom): (u32, u32, u32, u32), pixel: [u8; 4]) {
+    let bpp = frame.format.bytes_per_pixel();
+    for y in top..bottom {
+        for x in left..right {
+            let i = (y * frame.stride + x * bpp) as usize;
+            frame.data[i..i + bpp as usize].copy_from_slice(&pixel[..bpp as usize]);
+        }
+    }
+}
+
+// every channel on its own, which does for any pixel format
+fn average(frame: &Frame, (left, top, right, bottom): (u32, u32, u32, u32)) -> [u8; 4] {
+    let bpp = frame.format.bytes_per_pixel();
+    let mut sums = [0u32; 4];
+    for y in top..bottom {
+        let start = (y * frame.stride + left * bpp) as usize;
+        for px in frame.data[start..start + ((right - left) * bpp) as usize].chunks_exact(bpp as usize) {
+            sums.iter_mut().zip(px).for_each(|(s, c)| *s += *c as u32);
+        }
+    }
+    let count = (right - left) * (bottom - top);
+    sums.map(|s| (s / count) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capturer::PixelFormat;
+
+    fn white(w: u32, h: u32) -> Frame {
+        Frame::new(w, h, PixelFormat::Rgb, vec![255; (w * h * 3) as usize])
+    }
+
+    #[test]
+    fn masks_are_placed_on_the_source() {
+        // the frame is cropped at (100, 50) of display 1
+        let area = Area::new(100, 50, 8, 4, 1);
+        let mut frame = white(8, 4);
+        let masks = [
+            Mask { area: Area::new(98, 51, 4, 2, 1), style: MaskStyle::Solid },
+            // same place, another display
+            Mask { area: Area::new(104, 50, 4, 4, 0), style: MaskStyle::Solid },
+        ];
+        apply(&mut frame, &area, &masks);
+        for y in 0..4 {
+            for x in 0..8 {
+                let black = x < 2 && (1..3).contains(&y);
+                assert_eq!(frame.row(y)[(x * 3) as usize] == 0, black, "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn pixelation_averages_blocks_aligned_on_the_source() {
+        let area = Area::new(PIXEL_BLOCK - 2, 0, 4, 1, 0);
+        let mut frame = Frame::new(4, 1, PixelFormat::Rgb, vec![0, 0, 0, 100, 100, 100, 10, 20, 30, 30, 40, 50]);
+        apply(&mut frame, &area, &[Mask { area: Area::new(0, 0, 100, 100, 0), style: MaskStyle::Pixelate }]);
+        // the first two pixels end a block, the last two start the next one
+        assert_eq!(frame.data, [50, 50, 50, 50, 50, 50, 20, 30, 40, 20, 30, 40]);
+    }
+}
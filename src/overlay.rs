@@ -0,0 +1,167 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::capturer::{Frame, PixelFormat};
+use crate::{font, util};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+impl Corner {
+    pub const ALL: [(Corner, &'static str); 4] = [
+        (Corner::TopLeft, "Top left"),
+        (Corner::TopRight, "Top right"),
+        (Corner::BottomLeft, "Bottom left"),
+        (Corner::BottomRight, "Bottom right"),
+    ];
+    pub fn name(self) -> &'static str {
+        Corner::ALL.iter().find(|(c, _)| *c == self).map_or("", |(_, name)| name)
+    }
+}
+
+/// What the overlay burns into the frames, and where.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OverlayOptions {
+    pub caster: String, // not drawn when empty
+    pub time: bool,     // wall-clock date and time, in UTC
+    pub frame_number: bool,
+    pub logo: Option<PathBuf>, // drawn above the text, at its own size
+    pub corner: Corner,
+    pub opacity: f32, // from 0, invisible, to 1
+}
+
+impl Default for OverlayOptions {
+    fn default() -> Self {
+        Self { caster: String::new(), time: true, frame_number: true, logo: None, corner: Corner::default(), opacity: 0.8 }
+    }
+}
+
+/// Burns a watermark into each frame: the logo and the caster name, time and frame number, in
+/// white on a darker box so that they stay readable on any content.
+pub struct Overlay {
+    options: OverlayOptions,
+    logo: Option<Frame>, // RGBA, its alpha is honoured
+}
+
+impl Overlay {
+    /// `logo` replaces the one of `options`, which may not have loaded, see `load_logo`.
+    pub fn new(options: OverlayOptions, logo: Option<Frame>) -> Self {
+        Self { options, logo }
+    }
+    /// Draws the overlay on `frame`, whose number in its track is `frame_number`.
+    pub fn draw(&self, frame: &mut Frame, frame_number: u32) {
+        let opacity = self.options.opacity.clamp(0.0, 1.0);
+        let mut lines = Vec::new();
+        if !self.options.caster.is_empty() {
+            lines.push(self.options.caster.clone());
+        }
+        if self.options.time {
+            lines.push(utc(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()));
+        }
+        if self.options.frame_number {
+            lines.push(format!("#{frame_number}"));
+        }
+        if lines.is_empty() && self.logo.is_none() {
+            return;
+        }
+
+        // the text is sized for the frame, so that it stays readable whatever the output size
+        let scale = (frame.h / 360).max(1);
+        let (pad, line_height) = (2 * scale, (font::GLYPH_HEIGHT + 2) * scale);
+        let (logo_w, logo_h) = self.logo.as_ref().map_or((0, 0), |l| (l.w, l.h + pad));
+        let text_w = lines.iter().map(|l| font::text_width(l, scale)).max().unwrap_or(0);
+        let w = text_w.max(logo_w) + 2 * pad;
+        let h = logo_h + lines.len() as u32 * line_height + 2 * pad;
+        let margin = 4 * scale;
+        let left = match self.options.corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight => frame.w.saturating_sub(w + margin),
+        };
+        let top = match self.options.corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight => frame.h.saturating_sub(h + margin),
+        };
+
+        let black = frame.format.encode([0, 0, 0]);
+        for y in top..top + h {
+            for x in left..left + w {
+                blend(frame, x, y, &black, opacity / 2.0);
+            }
+        }
+        if let Some(logo) = &self.logo {
+            for y in 0..logo.h {
+                for (x, px) in logo.row(y).chunks_exact(4).enumerate() {
+                    let pixel = frame.format.encode([px[0], px[1], px[2]]);
+                    blend(frame, left + pad + x as u32, top + pad + y, &pixel, opacity * px[3] as f32 / 255.0);
+                }
+            }
+        }
+        let white = frame.format.encode([255, 255, 255]);
+        for (i, line) in lines.iter().enumerate() {
+            let (x, y) = (left + pad, top + pad + logo_h + i as u32 * line_height + scale);
+            for (dx, dy) in font::pixels(line, scale) {
+                blend(frame, x + dx, y + dy, &white, opacity);
+            }
+        }
+    }
+}
+
+/// Loads a logo for the overlay, whatever its image format.
+pub fn load_logo(path: &Path) -> io::Result<Frame> {
+    let rgba = image::open(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?.into_rgba8();
+    Ok(Frame::new(rgba.width(), rgba.height(), PixelFormat::Rgba, rgba.into_raw()))
+}
+
+// mixes `pixel`, in the format of the frame, into the pixel at (x, y), clipping at the border;
+// blending each channel works for every format, YUV included, as they are affine in RGB
+fn blend(frame: &mut Frame, x: u32, y: u32, pixel: &[u8; 4], alpha: f32) {
+    if x >= frame.w || y >= frame.h || alpha <= 0.0 {
+        return;
+    }
+    let bpp = frame.format.bytes_per_pixel() as usize;
+    let i = (y * frame.stride) as usize + x as usize * bpp;
+    for (dst, src) in frame.data[i..i + bpp].iter_mut().zip(pixel) {
+        *dst = (*dst as f32 + (*src as f32 - *dst as f32) * alpha).round() as u8;
+    }
+}
+
+/// `secs` since the epoch as a UTC date and time, e.g. "2024-03-01 13:45:07 UTC".
+fn utc(secs: u64) -> String {
+    let [year, month, day, hour, minute, second] = util::utc_date_time(secs);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02} UTC")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_in_utc() {
+        assert_eq!(utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(utc(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(utc(1_709_300_707), "2024-03-01 13:45:07 UTC");
+    }
+
+    #[test]
+    fn overlay_is_drawn_in_its_corner() {
+        let grey = || Frame::new(320, 180, PixelFormat::Rgb, vec![128; 320 * 180 * 3]);
+        let options = OverlayOptions { caster: "Alice".to_string(), time: false, frame_number: false, ..Default::default() };
+        let mut frame = grey();
+        Overlay::new(options.clone(), None).draw(&mut frame, 1);
+        // the box darkens the bottom right corner only, the text is lighter
+        assert!(frame.row(175)[300 * 3] < 128 && frame.row(170)[310 * 3] > 128);
+        assert!(frame.row(175)[318 * 3] == 128 && frame.row(10)[10 * 3] == 128 && frame.row(170)[10 * 3] == 128);
+
+        let mut frame = grey();
+        Overlay::new(OverlayOptions { opacity: 0.0, ..options }, None).draw(&mut frame, 1);
+        assert!(frame.data.iter().all(|c| *c == 128));
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::SystemTime;
+use crate::capturer::{CaptureError, Frame, FrameSource, PixelFormat};
+use crate::font;
+
+// colour bars: white, yellow, cyan, green, magenta, red, blue
+const BARS: [[u8; 3]; 7] = [
+    [192, 192, 192],
+    [192, 192, 0],
+    [0, 192, 192],
+    [0, 192, 0],
+    [192, 0, 192],
+    [192, 0, 0],
+    [0, 0, 192],
+];
+const GRADIENT_SPEED: u32 = 4; // pixels per frame
+
+/// A synthetic source that needs no display: colour bars over a moving gradient, with the frame
+/// counter and the wall-clock time burnt in. Useful for development, tests and demos.
+pub struct TestPattern {
+    width: u32,
+    height: u32,
+    frame_number: u32,
+    frame: Frame, // drawn again in place for every frame
+}
+
+impl TestPattern {
+    pub fn new(width: u32, height: u32) -> Self {
+        let (width, height) = (width.max(1), height.max(1));
+        let frame = Frame::new(width, height, PixelFormat::Bgra, vec![0; (width * height * 4) as usize]);
+        Self { width, height, frame_number: 0, frame }
+    }
+}
+
+impl FrameSource for TestPattern {
+    fn width(&self) -> u32 {
+        self.width
+    }
+    fn height(&self) -> u32 {
+        self.height
+    }
+    fn frame(&mut self) -> Result<&Frame, CaptureError> {
+        self.frame_number += 1;
+        let (w, h) = (self.width, self.height);
+        let frame = &mut self.frame;
+
+        let bars = BARS.map(|rgb| PixelFormat::Bgra.encode(rgb));
+        let bars_height = h * 2 / 3;
+        let shift = self.frame_number * GRADIENT_SPEED;
+        for (y, row) in frame.data.chunks_exact_mut(frame.stride as usize).enumerate() {
+            for (x, px) in row[..(w * 4) as usize].chunks_exact_mut(4).enumerate() {
+                if (y as u32) < bars_height {
+                    px.copy_from_slice(&bars[x * BARS.len() / w as usize]);
+                } else {
+                    let v = ((x as u32 + shift) % w * 255 / w) as u8;
+                    px.copy_from_slice(&[v, v, v, 255]);
+                }
+            }
+        }
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let secs = now.as_secs();
+        let text = format!(
+            "#{} {:02}:{:02}:{:02}.{:03}",
+            self.frame_number,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60,
+            now.subsec_millis()
+        );
+        let scale = (h / 120).max(1);
+        let margin = 2 * scale;
+        fill_rect(frame, font::text_width(&text, scale) + 2 * margin, (font::GLYPH_HEIGHT + 2) * scale, [0, 0, 0]);
+        font::draw_text(frame, margin, scale, &text, scale, [255, 255, 255]);
+        Ok(&self.frame)
+    }
+}
+
+/// Fills the top left corner of a frame, clipping at the frame border.
+fn fill_rect(frame: &mut Frame, width: u32, height: u32, rgb: [u8; 3]) {
+    let bpp = frame.format.bytes_per_pixel();
+    let pixel = frame.format.encode(rgb);
+    for y in 0..height.min(frame.h) {
+        for x in 0..width.min(frame.w) {
+            let i = (y * frame.stride + x * bpp) as usize;
+            frame.data[i..i + bpp as usize].copy_from_slice(&pixel[..bpp as usize]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_draws_colour_bars_over_a_moving_gradient() {
+        let mut pattern = TestPattern::new(350, 240);
+        let first = pattern.frame().unwrap().clone();
+        assert_eq!((first.w, first.h, first.format), (350, 240, PixelFormat::Bgra));
+        assert!(first.is_consistent() && first.is_packed());
+        let rgb = first.into_format(PixelFormat::Rgb);
+        let pixel = |x: u32, y: u32| -> [u8; 3] { rgb.data[((y * 350 + x) * 3) as usize..][..3].try_into().unwrap() };
+        // in the middle of each bar, below the burnt-in text
+        for (i, bar) in BARS.iter().enumerate() {
+            assert_eq!(pixel(i as u32 * 50 + 25, 100), *bar, "bar {i}");
+        }
+        // the gradient moves from one frame to the next, the bars below the text do not
+        let second = pattern.frame().unwrap().clone().into_format(PixelFormat::Rgb);
+        let (text_len, bars_len) = ((20 * 350 * 3) as usize, (160 * 350 * 3) as usize);
+        assert_eq!(second.data[text_len..bars_len], rgb.data[text_len..bars_len]);
+        assert_ne!(second.data[bars_len..], rgb.data[bars_len..]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufWriter, Write};
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use crate::capturer::{Frame, PixelFormat};
+use crate::receiver::FrameSink;
+
+pub const RAW_MAGIC: &[u8; 4] = b"SCRF";
+const JPEG_QUALITY: u8 = 90;
+
+/// How frames are written to the pipe. Both are self-describing:
+/// - `Raw`: for each frame, `RAW_MAGIC`, then width, height and payload length as little-endian
+///   u32, then the RGB24 pixels;
+/// - `Mjpeg`: concatenated JPEG images, readable with `ffmpeg -f mjpeg -i -`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PipeFormat {
+    Raw,
+    Mjpeg,
+}
+
+pub struct PipeSink {
+    writer: Box<dyn Write + Send>,
+    format: PipeFormat,
+}
+
+impl PipeSink {
+    /// `output` is a path, possibly of a named pipe, or "-" for stdout.
+    pub fn open(output: &str, format: PipeFormat) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = if output == "-" {
+            Box::new(BufWriter::new(io::stdout()))
+        } else {
+            Box::new(BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(output)?))
+        };
+        Ok(Self { writer, format })
+    }
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let frame = frame.to_format(PixelFormat::Rgb);
+        match self.format {
+            PipeFormat::Raw => {
+                self.writer.write_all(RAW_MAGIC)?;
+                self.writer.write_all(&frame.w.to_le_bytes())?;
+                self.writer.write_all(&frame.h.to_le_bytes())?;
+                self.writer.write_all(&(frame.data.len() as u32).to_le_bytes())?;
+                self.writer.write_all(&frame.data)?;
+            }
+            PipeFormat::Mjpeg => {
+                JpegEncoder::new_with_quality(&mut self.writer, JPEG_QUALITY)
+                    .encode(&frame.data, frame.w, frame.h, ColorType::Rgb8)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+        // consumers see each frame as soon as it arrives
+        self.writer.flush()
+    }
+}
+
+impl FrameSink for PipeSink {
+    fn deliver(&mut self, frame: Frame) -> bool {
+        match self.write(&frame) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Impossible writing frame to the output: {e}");
+                false
+            }
+        }
+    }
+}
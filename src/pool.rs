@@ -0,0 +1,23 @@
+// buffers kept for reuse; a frame is rarely held for longer than it takes to send it
+const MAX_FREE: usize = 4;
+
+/// Recycles frame buffers, so that the sender stops allocating once the first frames are sent.
+#[derive(Default)]
+pub struct BufferPool {
+    free: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// A buffer with whatever length and content it had when it was given back.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_default()
+    }
+    pub fn give(&mut self, buffer: Vec<u8>) {
+        if self.free.len() < MAX_FREE {
+            self.free.push(buffer);
+        }
+    }
+}
@@ -1,137 +1,388 @@
-use std::{fs};
-use std::io::Read;
+// diagnostics go to stderr, as stdout may carry the frames (see the pipe module)
+use std::collections::{BTreeMap, HashMap};
+use std::{fs, thread};
 use std::io;
-use std::net::{TcpListener};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{Receiver, Sender};
-use std::time::SystemTime;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use eframe::egui::Context;
 use std::process::Command;
-use tokio::runtime::Runtime;
+use crate::avi::AviWriter;
 use crate::capturer::Frame;
-use crate::util::{Header, CHUNK_SIZE, Message, MessageType};
+use crate::clip::ClipBuffer;
+use crate::depth;
+use crate::recorder::{ImageSequenceWriter, RecordSettings, Recorder, VideoFormat};
+use crate::stats::StatsRecorder;
+use crate::tracks::{RecordMode, TrackLayout, TrackSet};
+use crate::transfer::Transfers;
+use crate::util::{read_packet, write_packet, Event, Message, MessageType, PacketType};
 
-const PATH: &str = "./tmp";
+const ACCEPT_SLEEP: Duration = Duration::from_millis(100);
+// the rate the sender aims for; frames are placed by when they arrived, see AviWriter
+const VIDEO_FPS: u32 = 30;
 
-fn make_video() {
-    let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+/// Where the receiver delivers the frames: the gui, or a pipe when running headless.
+pub trait FrameSink {
+    /// Returns false when the sink is gone and the receiver should stop.
+    fn deliver(&mut self, frame: Frame) -> bool;
+    /// Called when something other than a frame changed, e.g. a file transfer progressed.
+    fn refresh(&self) {}
+    /// Whether the frames received lately are kept for clips, which the gui cuts.
+    fn keeps_clips(&self) -> bool {
+        false
+    }
+}
+
+pub struct GuiSink {
+    frame_s: Sender<Frame>,
+    ctx: Context,
+}
+impl GuiSink {
+    pub fn new(frame_s: Sender<Frame>, ctx: Context) -> Self {
+        Self { frame_s, ctx }
+    }
+}
+impl FrameSink for GuiSink {
+    fn deliver(&mut self, frame: Frame) -> bool {
+        if let Err(e) = self.frame_s.send(frame) {
+            eprintln!("Impossible sending frame via channel: {:?}", e);
+            return false;
+        }
+        self.ctx.request_repaint();
+        true
+    }
+    fn refresh(&self) {
+        self.ctx.request_repaint();
+    }
+    fn keeps_clips(&self) -> bool {
+        true
+    }
+}
 
+/// Transcodes the video `input` to H.264 in `output` with ffmpeg, returning whether it worked.
+fn transcode(input: &Path, output: &Path) -> bool {
     let ffmpeg_command = Command::new("ffmpeg")
-        .arg("-framerate")
-        .arg("10")
+        .arg("-y")
         .arg("-i")
-        .arg("./tmp/%d_img.jpeg")
+        .arg(input)
         .arg("-c:v")
         .arg("libx264")
-        .arg("-r")
-        .arg("10")
         .arg("-pix_fmt")
         .arg("yuv420p")
-        .arg(format!("./video_{ts}.mp4"))
+        .arg(output)
         .output();
 
     match ffmpeg_command {
-        Ok(output) => {
-            if !output.status.success() {
-                println!("ffmpeg failed with status: {:?}", output.status);
-            } else {
-                println!("Video created!");
+        Ok(result) => {
+            if !result.status.success() {
+                eprintln!("ffmpeg failed with status: {:?}", result.status);
+                return false;
             }
+            eprintln!("Video {} created!", output.display());
+            true
         }
         Err(e) => {
             if e.kind() == io::ErrorKind::NotFound {
-                println!("You don't have ffmpeg installed.");
+                eprintln!("You don't have ffmpeg installed");
             } else {
-                println!("Error executing ffmpeg: {}", e);
+                eprintln!("Error executing ffmpeg: {}", e);
             }
+            false
         }
     }
 }
 
-pub fn start(frame_s: Sender<Frame>, msg_r: Receiver<Message>, ctx: Context, mut save_option: bool) {
+/// A new hidden directory in `directory` for the temporary files of a session, unique to this
+/// process and session so that receivers sharing a host never touch each other's files. Being next
+/// to the recordings, it takes storage rather than the memory of a tmpfs, and its files are moved
+/// to them by a rename.
+fn session_dir(directory: &Path, started: u64) -> io::Result<PathBuf> {
+    for n in 0.. {
+        let dir = directory.join(format!(".screencasting_{}_{started}_{n}", std::process::id()));
+        match fs::create_dir(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|_| dir),
+        }
+    }
+    unreachable!()
+}
 
-    //initialization
-    let tokio_rt = Runtime::new().unwrap();
-    let ip_addr = local_ip_address::local_ip().unwrap().to_string();
-    let listener = TcpListener::bind(format!("{ip_addr}:8080")).unwrap();
-    println!("Server listening to {ip_addr}:8080");
-    fs::create_dir_all(PATH).unwrap(); // useful to record the streaming
-    let (mut stream, _) = listener.accept().unwrap();
-    let mut header_buffer = [0; std::mem::size_of::<Header>()];
-    let mut frame_buffer = [0; CHUNK_SIZE as usize];
-
-    'streaming: loop {
-        //manage messages from gui
+/// The videos written as the frames arrive while saving: the tracks side by side, or one for each
+/// track, placed and named as `settings` say.
+struct Recording {
+    settings: RecordSettings,
+    caster: String, // address of the sender
+    started: u64,   // s since UNIX epoch
+    videos: BTreeMap<Option<u32>, Video>, // by track, none for the composite
+    temp: Option<PathBuf>, // where the videos to transcode are written, created with the first one
+}
+
+struct Video {
+    path: PathBuf,              // where it is saved
+    written: PathBuf,           // where it is written meanwhile
+    recorder: Option<Recorder>, // none if it could not be started
+}
+
+impl Recording {
+    fn new(settings: &RecordSettings, caster: &str) -> Self {
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        Self { settings: settings.clone(), caster: caster.to_string(), started, videos: BTreeMap::new(), temp: None }
+    }
+    /// Adds `frame` to the video of `track`, or of the composite, starting it on the first frame.
+    fn record(&mut self, track: Option<u32>, frame: &Frame) {
+        let Self { settings, caster, started, videos, temp } = self;
+        let video = videos.entry(track).or_insert_with(|| {
+            let path = settings.path(caster, *started, track);
+            // a video that cannot be written is not retried with every frame
+            match open_writer(settings, &path, *started, temp) {
+                Ok((recorder, written)) => Video { path, written, recorder: Some(recorder) },
+                Err(e) => {
+                    eprintln!("Impossible recording {}: {e}", path.display());
+                    Video { written: path.clone(), path, recorder: None }
+                }
+            }
+        });
+        if let Some(r) = &mut video.recorder {
+            r.record(frame);
+        }
+    }
+    /// Completes the videos, transcoding them if the format asks for it; a video that could not be
+    /// transcoded is kept as AVI.
+    fn finish(self) {
+        for Video { path, written, recorder } in self.videos.into_values() {
+            let Some(recorder) = recorder else { continue };
+            // waits for the frames queued to be written
+            drop(recorder);
+            if self.settings.format == VideoFormat::Mp4 && !transcode(&written, &path) {
+                let kept = path.with_extension("avi");
+                match fs::rename(&written, &kept) {
+                    Ok(_) => eprintln!("The video is kept as {}", kept.display()),
+                    Err(e) => eprintln!("Impossible keeping the video as {}: {e}", kept.display()),
+                }
+            }
+        }
+        if let Some(temp) = self.temp {
+            if let Err(e) = fs::remove_dir_all(&temp) {
+                eprintln!("Impossible removing {}: {e}", temp.display());
+            }
+        }
+    }
+}
+
+// the recorder writing to `path` in the format of `settings`, and the file it writes to: an MP4
+// starts as an AVI in the temporary directory of the session, `temp`, created if needed
+fn open_writer(settings: &RecordSettings, path: &Path, started: u64, temp: &mut Option<PathBuf>) -> io::Result<(Recorder, PathBuf)> {
+    fs::create_dir_all(&settings.directory)?;
+    let path = match settings.format {
+        VideoFormat::Avi => path.with_extension("avi"),
+        VideoFormat::Mp4 => {
+            let temp = match temp {
+                Some(temp) => temp,
+                None => temp.insert(session_dir(&settings.directory, started)?),
+            };
+            temp.join(path.file_name().unwrap_or_default()).with_extension("avi")
+        }
+        VideoFormat::Images => path.to_path_buf(),
+    };
+    let recorder = match settings.format {
+        VideoFormat::Avi | VideoFormat::Mp4 => Recorder::with_writer(AviWriter::create(&path, VIDEO_FPS, settings.quality)?, path.clone()),
+        VideoFormat::Images => Recorder::with_writer(ImageSequenceWriter::create(&path, settings.quality)?, path.clone()),
+    };
+    Ok((recorder, path))
+}
+
+// exports the clip `msg` asks for in the background, telling the gui when it is done
+fn export_clip(clips: Option<&ClipBuffer>, msg: &Message, event_s: &Sender<Event>) {
+    let (Some(clips), Some((from, to))) = (clips, msg.clip) else { return; };
+    let clip = clips.cut(from, to);
+    let (options, output, event_s) = (msg.gif_options.clone(), msg.path.clone(), event_s.clone());
+    thread::spawn(move || {
+        let frames = clip.and_then(|clip| {
+            fs::create_dir_all(output.parent().unwrap_or(Path::new(".")))?;
+            clip.export(&options, &output)
+        });
+        let _ = event_s.send(Event::ClipExported(frames.map(|frames| (output, frames)).map_err(|e| e.to_string())));
+    });
+}
+
+/// Waits for the sender to connect, returning `None` if the gui asks to stop meanwhile.
+fn accept(listener: &TcpListener, msg_r: &Receiver<Message>, save_option: &mut bool, layout: &mut TrackLayout, record_mode: &mut RecordMode, clips: Option<&ClipBuffer>, event_s: &Sender<Event>) -> Option<TcpStream> {
+    loop {
         if let Ok(msg) = msg_r.try_recv() {
             match msg.message_type {
                 MessageType::Stop => {
-                    println!("received stop request from gui");
-                    break 'streaming;
+                    eprintln!("received stop request from gui");
+                    return None;
                 }
                 MessageType::Save => {
-                    save_option = msg.save_option;
+                    *save_option = msg.save_option;
+                }
+                MessageType::Tracks => {
+                    *layout = msg.layout;
+                    *record_mode = msg.record_mode;
                 }
+                MessageType::Clip => export_clip(clips, &msg, event_s),
                 _ => {}
             }
         }
-
-        // Read header
-        if let Err(e) = stream.read_exact(&mut header_buffer) {
-            println!("Connection closed: {e}");
-            break 'streaming;
-        }
-        let mut header: Header = bincode::deserialize(&header_buffer).expect("error deserializing header");
-        println!("Header received {} {}", header.frame_number, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis());
-
-        // Read data
-        let mut data = Vec::with_capacity(header.len as usize);
-        while header.len > 0 {
-            if let Err(e) = stream.read_exact(&mut frame_buffer) {
-                println!("connection closed: {e}");
-                break 'streaming;
-            }
-            let end;
-            if CHUNK_SIZE > header.len {
-                end = header.len;
-                header.len = 0;
-            } else {
-                end = CHUNK_SIZE;
-                header.len = header.len - CHUNK_SIZE;
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // the accepted stream may inherit the non-blocking mode of the listener
+                if let Err(e) = stream.set_nonblocking(false) {
+                    eprintln!("Impossible configuring connection: {e}");
+                    return None;
+                }
+                return Some(stream);
             }
-            for i in 0..end {
-                data.push(frame_buffer[i as usize]);
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_SLEEP),
+            Err(e) => {
+                eprintln!("Impossible accepting connection: {e}");
+                return None;
             }
         }
-        println!("Frame received {} {}", header.frame_number, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis());
-
-        // Save frame
-        let frame_number = header.frame_number;
-        match image::RgbImage::from_raw(header.frame_width, header.frame_height, data.clone()) {
-            None => { println!("error occurs converting frame {frame_number} in RgbImage"); }
-            Some(rgb) => {
-                tokio_rt.spawn(async move {
-                    if let Err(e) = rgb.save(format!("{PATH}/{frame_number}_img.jpeg")) {
-                        println!("Error occurs saving image {frame_number}: {e}");
+    }
+}
+
+/// Shows the tracks of the cast as `layout` says. While `save_option` is set, they are recorded as
+/// `record_mode` and `settings` say. Recordings are finished on threads of their own, so that
+/// neither the cast nor a stop waits for them; the one finishing the last recording is returned, for
+/// a process about to exit to wait for it.
+pub fn start(mut sink: impl FrameSink, msg_r: Receiver<Message>, event_s: Sender<Event>, mut save_option: bool, mut layout: TrackLayout, mut record_mode: RecordMode, settings: RecordSettings) -> Option<JoinHandle<()>> {
+
+    //initialization
+    let ip_addr = local_ip_address::local_ip().unwrap().to_string();
+    let listener = TcpListener::bind(format!("{ip_addr}:8080")).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    eprintln!("Server listening to {ip_addr}:8080");
+    let mut stats = StatsRecorder::new();
+    let mut connections = 0;
+    // kept across reconnections, so that a cast interrupted by the network stays in one video
+    let mut recording: Option<Recording> = None;
+    let mut tracks = TrackSet::new();
+    let mut resolutions = HashMap::new();
+    // kept across reconnections too, so that a clip may show the cast before the network failed
+    let clips = sink.keeps_clips().then(ClipBuffer::start);
+
+    // the sender may disconnect and come back, until the gui asks to stop
+    'listening: while let Some(mut stream) = accept(&listener, &msg_r, &mut save_option, &mut layout, &mut record_mode, clips.as_ref(), &event_s) {
+        connections += 1;
+        if connections > 1 {
+            eprintln!("Sender reconnected");
+            stats.reconnect();
+        }
+        let mut transfers = Transfers::new(event_s.clone());
+        tracks.reset_numbers();
+        let caster = stream.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+
+        'streaming: loop {
+            //manage messages from gui
+            if let Ok(msg) = msg_r.try_recv() {
+                match msg.message_type {
+                    MessageType::Stop => {
+                        eprintln!("received stop request from gui");
+                        break 'listening;
+                    }
+                    MessageType::Save => {
+                        save_option = msg.save_option;
+                    }
+                    MessageType::Tracks => {
+                        layout = msg.layout;
+                        record_mode = msg.record_mode;
                     }
-                });
+                    MessageType::SendFile => transfers.offer(&msg.path),
+                    MessageType::AcceptFile => transfers.accept(msg.file_id, &msg.path),
+                    MessageType::DeclineFile => transfers.decline(msg.file_id),
+                    MessageType::Clip => export_clip(clips.as_ref(), &msg, &event_s),
+                    _ => {}
+                }
             }
-        }
 
-        // Send frame to gui
-        let frame = Frame::new(header.frame_width, header.frame_height, data);
-        if let Err(e) = frame_s.send(frame) {
-            println!("Impossible sending frame via channel: {:?}", e);
-            break 'streaming;
-        }
+            // Send file packets, one batch for each packet received
+            for (header, payload) in transfers.outgoing_packets() {
+                if let Err(e) = write_packet(&mut stream, &header, &payload) {
+                    eprintln!("Connection closed: {e}");
+                    break 'streaming;
+                }
+            }
 
-        ctx.request_repaint();
-    }
+            // Read packet
+            let (header, data) = match read_packet(&mut stream) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    eprintln!("Connection closed: {e}");
+                    break 'streaming;
+                }
+            };
+            if header.packet_type != PacketType::Frame {
+                transfers.handle(&header, &data);
+                sink.refresh();
+                continue;
+            }
+            let decode_start = Instant::now();
+            stats.latency(header.age());
+            stats.frame(data.len());
+            let track_count = tracks.len();
+            let resolution = ((header.frame_width, header.frame_height), (header.capture_width, header.capture_height));
+            if resolutions.get(&header.track) != Some(&resolution) {
+                resolutions.insert(header.track, resolution);
+                let _ = event_s.send(Event::Resolution { track: header.track, output: resolution.0, capture: resolution.1 });
+            }
+            let Some(frame) = depth::expand(header.depth, header.frame_width, header.frame_height, data) else {
+                eprintln!("Frame {} of track {} does not fit {}x{} in {}, skipped", header.frame_number, header.track, header.frame_width, header.frame_height, header.depth.name());
+                continue;
+            };
+            let dropped = tracks.update(header.track, header.frame_number, frame);
+            if dropped > 0 {
+                stats.dropped(dropped);
+            }
+            if tracks.len() > track_count {
+                let _ = event_s.send(Event::Tracks(tracks.len()));
+            }
 
-    if save_option {
-        make_video();
-    }
+            // Save frame: the composite is saved, and kept for clips, once per round of tracks, when
+            // the first one arrives
+            let composited = clips.is_some() || save_option && record_mode == RecordMode::Composited;
+            let composite = (header.track == 0 && composited).then(|| tracks.composite());
+            if let (Some(clips), Some(frame)) = (&clips, &composite) {
+                clips.record(frame);
+            }
+            if save_option {
+                let recording = recording.get_or_insert_with(|| Recording::new(&settings, &caster));
+                match record_mode {
+                    RecordMode::Composited => {
+                        if let Some(frame) = &composite {
+                            recording.record(None, frame);
+                        }
+                    }
+                    RecordMode::Separate => {
+                        if let Some(frame) = tracks.frame(header.track) {
+                            recording.record(Some(header.track), frame);
+                        }
+                    }
+                }
+            } else if let Some(recording) = recording.take() {
+                // joining the writers, or transcoding, would stall the cast
+                thread::spawn(move || recording.finish());
+            }
 
-    if let Err(e) = fs::remove_dir_all(PATH) {
-        println!("impossible remove dir tmp: {e}");
+            // Send frame to gui
+            let view = tracks.view(layout, header.track);
+            stats.decode(decode_start.elapsed());
+            if let Some(frame) = view {
+                if !sink.deliver(frame) {
+                    break 'listening;
+                }
+            }
+            if let Some(sample) = stats.sample() {
+                let _ = event_s.send(Event::Stats(sample));
+            }
+        }
+        eprintln!("Waiting for the sender to reconnect");
+        sink.refresh();
     }
-    println!("Receiver terminated.");
-}
\ No newline at end of file
+
+    eprintln!("Receiver terminated.");
+    recording.take().map(|recording| thread::spawn(move || recording.finish()))
+}
@@ -0,0 +1,306 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Instant;
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use serde::{Deserialize, Serialize};
+use crate::capturer::{Frame, PixelFormat};
+use crate::util;
+
+pub const SESSION_EXTENSION: &str = "scast";
+pub const SESSION_MAGIC: &[u8; 8] = b"SCAST01\n";
+const JPEG_QUALITY: u8 = 80;
+// frames waiting to be encoded; beyond this the recorder drops frames instead of slowing the caller
+const QUEUE_LEN: usize = 8;
+
+/// Precedes every JPEG frame of a session file.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RecordHeader {
+    pub timestamp: u64, // ms since the start of the recording
+    pub width: u32,
+    pub height: u32,
+    pub len: u32,
+}
+
+/// Where a `Recorder` writes the frames, on its own thread.
+pub trait FrameWriter: Send + 'static {
+    /// Writes a frame captured `timestamp` ms after the start of the recording.
+    fn write(&mut self, timestamp: u64, frame: &Frame) -> io::Result<()>;
+    /// Completes the recording, which stopped `timestamp` ms after its start.
+    fn finish(self, timestamp: u64) -> io::Result<()>;
+}
+
+/// Writes a session file: the magic string followed by a `RecordHeader` and a JPEG for each frame.
+pub struct SessionWriter {
+    file: BufWriter<File>,
+}
+
+impl SessionWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(SESSION_MAGIC)?;
+        Ok(Self { file })
+    }
+    pub fn write(&mut self, timestamp: u64, frame: &Frame) -> io::Result<()> {
+        let frame = frame.to_format(PixelFormat::Rgb);
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+            .encode(&frame.data, frame.w, frame.h, ColorType::Rgb8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let header = RecordHeader { timestamp, width: frame.w, height: frame.h, len: jpeg.len() as u32 };
+        bincode::serialize_into(&mut self.file, &header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.file.write_all(&jpeg)
+    }
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl FrameWriter for SessionWriter {
+    fn write(&mut self, timestamp: u64, frame: &Frame) -> io::Result<()> {
+        SessionWriter::write(self, timestamp, frame)
+    }
+    fn finish(self, _timestamp: u64) -> io::Result<()> {
+        SessionWriter::finish(self)
+    }
+}
+
+/// Position of a frame in a session file.
+pub struct RecordIndex {
+    pub timestamp: u64,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// Reads back a session file written by `SessionWriter`.
+pub struct SessionReader {
+    file: BufReader<File>,
+    index: Vec<RecordIndex>,
+}
+
+impl SessionReader {
+    /// Opens the file and indexes its frames, skipping a truncated last frame.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; SESSION_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if &magic != SESSION_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a session file"));
+        }
+        let size = file.get_ref().metadata()?.len();
+        let mut index = Vec::new();
+        while let Ok(header) = bincode::deserialize_from::<_, RecordHeader>(&mut file) {
+            let offset = file.stream_position()?;
+            if offset + header.len as u64 > size {
+                break;
+            }
+            index.push(RecordIndex { timestamp: header.timestamp, offset, len: header.len });
+            file.seek_relative(header.len as i64)?;
+        }
+        Ok(Self { file, index })
+    }
+    pub fn index(&self) -> &[RecordIndex] {
+        &self.index
+    }
+    /// Returns the JPEG data of the i-th frame.
+    pub fn read(&mut self, i: usize) -> io::Result<Vec<u8>> {
+        let record = &self.index[i];
+        let mut jpeg = vec![0; record.len as usize];
+        self.file.seek(SeekFrom::Start(record.offset))?;
+        self.file.read_exact(&mut jpeg)?;
+        Ok(jpeg)
+    }
+}
+
+/// Records RGB frames to a session file, or another `FrameWriter`, on a background thread, so that
+/// encoding and writing never delay the caller. Frames are dropped when the recorder cannot keep up.
+pub struct Recorder {
+    frame_s: Option<SyncSender<(Instant, Frame)>>,
+    handle: Option<JoinHandle<()>>,
+    dropped: u32,
+}
+
+impl Recorder {
+    pub fn start(path: PathBuf) -> io::Result<Self> {
+        Ok(Self::with_writer(SessionWriter::create(&path)?, path))
+    }
+    /// Records with `writer`, to `path`.
+    pub fn with_writer(mut writer: impl FrameWriter, path: PathBuf) -> Self {
+        let (frame_s, frame_r) = sync_channel::<(Instant, Frame)>(QUEUE_LEN);
+        let start = Instant::now();
+        let handle = thread::spawn(move || {
+            for (instant, frame) in frame_r {
+                let timestamp = instant.saturating_duration_since(start).as_millis() as u64;
+                if let Err(e) = writer.write(timestamp, &frame) {
+                    eprintln!("Impossible recording frame: {e}");
+                    return;
+                }
+            }
+            // on stderr, as the receiver may write the frames to stdout
+            match writer.finish(start.elapsed().as_millis() as u64) {
+                Ok(_) => eprintln!("Recording saved to {}", path.display()),
+                Err(e) => eprintln!("Impossible saving recording: {e}"),
+            }
+        });
+        Self { frame_s: Some(frame_s), handle: Some(handle), dropped: 0 }
+    }
+    pub fn record(&mut self, frame: &Frame) {
+        if let Some(s) = &self.frame_s {
+            let copy = frame.clone();
+            match s.try_send((Instant::now(), copy)) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => self.dropped += 1,
+                Err(TrySendError::Disconnected(_)) => self.frame_s = None,
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // closing the channel lets the thread finish the file
+        self.frame_s.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        if self.dropped > 0 {
+            eprintln!("Recorder dropped {} frames", self.dropped);
+        }
+    }
+}
+
+/// How the receiver saves the casts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VideoFormat {
+    /// MJPEG in AVI, written as the frames arrive.
+    #[default]
+    Avi,
+    /// H.264 in MP4: an AVI that ffmpeg transcodes at the end, kept if ffmpeg is missing.
+    Mp4,
+    /// A directory of `%d_img.jpeg` images, without timing.
+    Images,
+}
+
+impl VideoFormat {
+    pub const ALL: [(VideoFormat, &'static str); 3] = [
+        (VideoFormat::Avi, "MJPEG AVI"),
+        (VideoFormat::Mp4, "MP4 / H.264 (needs ffmpeg)"),
+        (VideoFormat::Images, "JPEG image sequence"),
+    ];
+    pub fn name(self) -> &'static str {
+        VideoFormat::ALL.iter().find(|(f, _)| *f == self).map_or("", |(_, name)| name)
+    }
+}
+
+/// Where and how the receiver saves the casts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordSettings {
+    pub directory: PathBuf,
+    /// Name of the files, without extension: `{session}`, `{caster}` and `{date}` are replaced by
+    /// the session name, the address of the sender and the UTC date and time of the start.
+    pub template: String,
+    pub session: String,
+    pub format: VideoFormat,
+    pub quality: u8, // of the JPEG images, from 1 to 100
+}
+
+impl Default for RecordSettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("./recordings"),
+            template: "{session}_{date}".to_string(),
+            session: "cast".to_string(),
+            format: VideoFormat::default(),
+            quality: JPEG_QUALITY,
+        }
+    }
+}
+
+impl RecordSettings {
+    /// Where the recording of `track`, or of the composite if none, of a cast from `caster` started
+    /// at `started` s since the epoch ends up: a file, or a directory for an image sequence. An
+    /// existing one is never overwritten, a number is added to the name instead.
+    pub fn path(&self, caster: &str, started: u64, track: Option<u32>) -> PathBuf {
+        let [year, month, day, hour, minute, second] = util::utc_date_time(started);
+        let date = format!("{year:04}-{month:02}-{day:02}_{hour:02}-{minute:02}-{second:02}");
+        let mut name = self.template.replace("{session}", &self.session).replace("{caster}", caster).replace("{date}", &date);
+        if let Some(t) = track {
+            name.push_str(&format!("_track{t}"));
+        }
+        // whatever the template, the name stays a single valid file name
+        let name: String = name.chars().map(|c| if c.is_control() || r#"/\:*?"<>|"#.contains(c) { '_' } else { c }).collect();
+        let name = if name.trim().is_empty() { "recording".to_string() } else { name };
+        let extension = match self.format {
+            VideoFormat::Avi => ".avi",
+            VideoFormat::Mp4 => ".mp4",
+            VideoFormat::Images => "",
+        };
+        (1..)
+            .map(|n| if n == 1 { format!("{name}{extension}") } else { format!("{name}_{n}{extension}") })
+            .map(|file| self.directory.join(file))
+            // the AVI that becomes an MP4 is not overwritten either
+            .find(|path| !path.exists() && (self.format != VideoFormat::Mp4 || !path.with_extension("avi").exists()))
+            .unwrap()
+    }
+}
+
+/// Writes the frames as `%d_img.jpeg` images in a directory, numbered from 1.
+pub struct ImageSequenceWriter {
+    directory: PathBuf,
+    quality: u8,
+    count: u32,
+}
+
+impl ImageSequenceWriter {
+    pub fn create(directory: &Path, quality: u8) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        Ok(Self { directory: directory.to_path_buf(), quality, count: 0 })
+    }
+}
+
+impl FrameWriter for ImageSequenceWriter {
+    fn write(&mut self, _timestamp: u64, frame: &Frame) -> io::Result<()> {
+        let frame = frame.to_format(PixelFormat::Rgb);
+        self.count += 1;
+        let file = BufWriter::new(File::create(self.directory.join(format!("{}_img.jpeg", self.count)))?);
+        JpegEncoder::new_with_quality(file, self.quality)
+            .encode(&frame.data, frame.w, frame.h, ColorType::Rgb8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    fn finish(self, _timestamp: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recordings_are_named_after_the_template() {
+        let directory = std::env::temp_dir().join(format!("recordings_test_{}", std::process::id()));
+        let settings = RecordSettings {
+            directory: directory.clone(),
+            template: "{session}-{caster}-{date}".to_string(),
+            session: "demo/1".to_string(),
+            ..Default::default()
+        };
+        let path = settings.path("10.0.0.2", 1_709_300_707, Some(1));
+        assert_eq!(path, directory.join("demo_1-10.0.0.2-2024-03-01_13-45-07_track1.avi"));
+
+        // existing recordings are kept
+        fs::create_dir_all(&directory).unwrap();
+        File::create(&path).unwrap();
+        assert_eq!(settings.path("10.0.0.2", 1_709_300_707, Some(1)), directory.join("demo_1-10.0.0.2-2024-03-01_13-45-07_track1_2.avi"));
+        let images = RecordSettings { format: VideoFormat::Images, template: String::new(), ..settings };
+        assert_eq!(images.path("", 0, None), directory.join("recording"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use image::DynamicImage;
+use crate::avi::AviReader;
+use crate::capturer::{CaptureError, Frame, FrameSource, PixelFormat};
+use crate::recorder::{SessionReader, SESSION_EXTENSION};
+
+// image sequences carry no timing: they are replayed at the rate the sender aims for
+const SEQUENCE_FRAME_MS: u64 = 1000 / 30;
+
+enum Frames {
+    Session(SessionReader),
+    Video(AviReader),
+    Sequence(Vec<PathBuf>),
+}
+
+/// Replays a session file, an AVI video or an image sequence as if it were a live screen.
+/// Frames are picked by their timestamp against the playback clock, so the original timing is
+/// kept whatever rate the sender asks for frames.
+pub struct ReplaySource {
+    frames: Frames,
+    timestamps: Vec<u64>, // ms since the start of the replay
+    looping: bool,
+    start: Instant,
+    current: usize,
+    frame: Frame,
+}
+
+impl ReplaySource {
+    /// `path` is a session file, an MJPEG AVI video as saved by the receiver, a pattern such as
+    /// `./shots/%d_img.jpeg`, or a directory holding `%d_img.jpeg` images.
+    pub fn open(path: &Path, looping: bool) -> io::Result<Self> {
+        let (frames, timestamps) = if path.extension().is_some_and(|e| e == SESSION_EXTENSION) {
+            let reader = SessionReader::open(path)?;
+            let timestamps: Vec<u64> = reader.index().iter().map(|r| r.timestamp).collect();
+            (Frames::Session(reader), timestamps)
+        } else if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("avi")) {
+            let reader = AviReader::open(path)?;
+            let timestamps = (0..reader.len()).map(|i| reader.timestamp(i)).collect();
+            (Frames::Video(reader), timestamps)
+        } else {
+            let paths = sequence(path)?;
+            let timestamps = (0..paths.len() as u64).map(|i| i * SEQUENCE_FRAME_MS).collect();
+            (Frames::Sequence(paths), timestamps)
+        };
+        if timestamps.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no frames in {}", path.display())));
+        }
+        let mut replay = Self { frames, timestamps, looping, start: Instant::now(), current: 0, frame: Frame::default() };
+        replay.frame = replay.decode(0)?;
+        Ok(replay)
+    }
+    fn duration(&self) -> Duration {
+        Duration::from_millis(self.timestamps[self.timestamps.len() - 1] + SEQUENCE_FRAME_MS)
+    }
+    /// When each frame shows, in ms from the start.
+    pub fn timestamps(&self) -> &[u64] {
+        &self.timestamps
+    }
+    /// Decodes the i-th frame to RGBA.
+    pub fn decode(&mut self, i: usize) -> io::Result<Frame> {
+        let image = match &mut self.frames {
+            Frames::Session(reader) => image::load_from_memory(&reader.read(i)?),
+            Frames::Video(reader) => image::load_from_memory(&reader.read(i)?),
+            Frames::Sequence(paths) => image::open(&paths[i]),
+        };
+        let rgba = image.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)).map(DynamicImage::into_rgba8)?;
+        Ok(Frame::new(rgba.width(), rgba.height(), PixelFormat::Rgba, rgba.into_raw()))
+    }
+}
+
+impl FrameSource for ReplaySource {
+    fn width(&self) -> u32 {
+        self.frame.w
+    }
+    fn height(&self) -> u32 {
+        self.frame.h
+    }
+    fn frame(&mut self) -> Result<&Frame, CaptureError> {
+        let duration = self.duration();
+        let mut elapsed = self.start.elapsed();
+        if elapsed >= duration && self.looping {
+            let loops = elapsed.as_millis() / duration.as_millis();
+            self.start += duration * loops as u32;
+            elapsed = self.start.elapsed();
+        }
+        let t = elapsed.as_millis() as u64;
+        let i = self.timestamps.partition_point(|&ts| ts <= t).saturating_sub(1);
+        if i != self.current {
+            match self.decode(i) {
+                Ok(frame) => {
+                    self.frame = frame;
+                    self.current = i;
+                }
+                Err(e) => {
+                    // the previous frame stays on, and the error is told once rather than with every frame
+                    println!("Impossible decoding frame {i}: {e}");
+                    self.current = i;
+                }
+            }
+        }
+        Ok(&self.frame)
+    }
+    fn seek(&mut self, position: Duration) {
+        let position = position.min(self.duration());
+        self.start = Instant::now().checked_sub(position).unwrap_or_else(Instant::now);
+    }
+    fn position(&self) -> Option<(Duration, Duration)> {
+        let duration = self.duration();
+        Some((self.start.elapsed().min(duration), duration))
+    }
+}
+
+/// Lists the images matching `path`, sorted by their number.
+fn sequence(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let pattern = if path.is_dir() { path.join("%d_img.jpeg") } else { path.to_path_buf() };
+    let dir = pattern.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let (prefix, suffix) = pattern
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.split_once("%d"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "an image sequence needs a %d in its name"))?;
+    let mut frames: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let number = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            Some((number.parse().ok()?, entry.path()))
+        })
+        .collect();
+    frames.sort();
+    Ok(frames.into_iter().map(|(_, path)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::SessionWriter;
+
+    fn frame(value: u8) -> Frame {
+        Frame::new(16, 8, PixelFormat::Rgb, vec![value; 16 * 8 * 3])
+    }
+
+    // a session of three frames, a second apart, of growing brightness
+    fn session(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("replay_test_{name}_{}.{SESSION_EXTENSION}", std::process::id()));
+        let mut writer = SessionWriter::create(&path).unwrap();
+        for (timestamp, value) in [(0, 0), (1000, 128), (2000, 255)] {
+            writer.write(timestamp, &frame(value)).unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn sessions_are_read_back() {
+        let path = session("read");
+        let mut replay = ReplaySource::open(&path, false).unwrap();
+        assert_eq!(replay.timestamps(), [0, 1000, 2000]);
+        assert_eq!((replay.width(), replay.height()), (16, 8));
+        let middle = replay.decode(1).unwrap();
+        assert_eq!(middle.format, PixelFormat::Rgba);
+        assert!(middle.data.chunks_exact(4).all(|p| p[0].abs_diff(128) < 4 && p[3] == 255));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn frames_are_picked_by_their_timestamp() {
+        let path = session("pick");
+        let mut replay = ReplaySource::open(&path, false).unwrap();
+        replay.frame().unwrap();
+        assert_eq!(replay.current, 0);
+        replay.seek(Duration::from_millis(1500));
+        replay.frame().unwrap();
+        assert_eq!(replay.current, 1);
+        let (position, duration) = replay.position().unwrap();
+        assert!(position >= Duration::from_millis(1500) && position < Duration::from_millis(2000), "{position:?}");
+        assert_eq!(duration, Duration::from_millis(2000 + SEQUENCE_FRAME_MS));
+        // seeking back works too, and past the end shows the last frame
+        replay.seek(Duration::ZERO);
+        replay.frame().unwrap();
+        assert_eq!(replay.current, 0);
+        replay.seek(Duration::from_secs(60));
+        replay.frame().unwrap();
+        assert_eq!(replay.current, 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_loop_only_when_asked() {
+        let path = session("loop");
+        for (looping, expected) in [(true, 1), (false, 2)] {
+            let mut replay = ReplaySource::open(&path, looping).unwrap();
+            // two loops and a half in
+            replay.start = Instant::now() - (replay.duration() * 2 + Duration::from_millis(1500));
+            replay.frame().unwrap();
+            assert_eq!(replay.current, expected, "looping {looping}");
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sequences_are_sorted_by_number() {
+        let dir = std::env::temp_dir().join(format!("replay_test_sequence_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["10_img.jpeg", "9_img.jpeg", "1_img.jpeg", "2_img.jpeg", "x_img.jpeg", "_img.jpeg", "3_img.png", "notes.txt"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let names = |paths: Vec<PathBuf>| -> Vec<String> { paths.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect() };
+        let expected = ["1_img.jpeg", "2_img.jpeg", "9_img.jpeg", "10_img.jpeg"];
+        assert_eq!(names(sequence(&dir).unwrap()), expected);
+        assert_eq!(names(sequence(&dir.join("%d_img.jpeg")).unwrap()), expected);
+        assert_eq!(names(sequence(&dir.join("%d_img.png")).unwrap()), ["3_img.png"]);
+        assert!(sequence(&dir.join("img.jpeg")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
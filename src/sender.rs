@@ -1,14 +1,146 @@
-use std::vec::Vec;
-use std::time::{SystemTime};
-use std::net::TcpStream;
-use std::io::Write;
-use std::sync::mpsc::Receiver;
-use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::{mem, thread};
+use image::imageops::FilterType;
+use crate::bandwidth::RateController;
 use crate::capturer;
-use crate::capturer::{Area, capture, Frame};
-use crate::util::{Header, CHUNK_SIZE, Message, MessageType};
+use crate::capturer::{Area, CaptureError, FrameSource, OutputSize, PixelFormat, SourceKind};
+use crate::follow::{self, Follower};
+use crate::mask::Mask;
+use crate::overlay::{Overlay, OverlayOptions};
+use crate::zoom::{Zoom, ZoomRequest, ZoomTarget};
+use crate::{depth, mask, overlay, recorder};
+use crate::depth::{ColorDepth, PaletteBuilder};
+use crate::pool::BufferPool;
+use crate::recorder::Recorder;
+use crate::stats::StatsRecorder;
+use crate::transfer::Transfers;
+use crate::util::{read_packet, write_packet, Event, Header, Message, MessageType, PacketType};
 
-pub fn start(ip_addr: String, mut area: Area, msg_r: Receiver<Message>) {
+fn start_recording() -> Option<Recorder> {
+    let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let path = PathBuf::from(format!("./cast_{ts}.{}", recorder::SESSION_EXTENSION));
+    match Recorder::start(path) {
+        Ok(r) => Some(r),
+        Err(e) => {
+            println!("Impossible recording locally: {e}");
+            None
+        }
+    }
+}
+
+/// A region of a source, cast as its own stream of frames.
+struct Track {
+    id: u32,
+    area: Area,
+    source: Option<Box<dyn FrameSource>>, // none once the source failed
+    frame_number: u32,
+    follower: Option<Follower>, // pans the area after the pointer
+    zoom: Zoom,
+}
+
+impl Track {
+    fn open(id: u32, source_kind: &SourceKind, area: Area, event_s: &Sender<Event>) -> Self {
+        let mut track = Self { id, area: Area::default(), source: None, frame_number: 0, follower: None, zoom: Zoom::default() };
+        track.set_area(source_kind, area, event_s);
+        track
+    }
+    /// Applies a new area. The screen is reopened, as the area may be on another display; other
+    /// sources only when they failed, so that a replay keeps its position.
+    fn set_area(&mut self, source_kind: &SourceKind, area: Area, event_s: &Sender<Event>) {
+        if *source_kind == SourceKind::Screen || self.source.is_none() {
+            println!("Track {}, selected display: {}", self.id, area.selected_display);
+            self.source = None;
+            match capturer::open(source_kind, area.selected_display) {
+                Ok(s) => self.source = Some(s),
+                Err(e) => self.lose(e, event_s),
+            }
+        }
+        self.area = match self.source.as_deref() {
+            Some(source) => fit_area(self.id, &area, &self.area, source, event_s),
+            None => area,
+        };
+    }
+    /// Makes the area follow the pointer, from where it is now, if `options` ask for it and the
+    /// pointer moves over the source.
+    fn follow(&mut self, source_kind: &SourceKind, options: &CastOptions) {
+        self.follower = None;
+        if !options.follow_mouse {
+            return;
+        }
+        match follow::source_origin(source_kind, self.area.selected_display, &options.display_offsets) {
+            Some(origin) => self.follower = Follower::new(origin, &self.area),
+            None => println!("Track {}, the pointer cannot be followed on this source", self.id),
+        }
+    }
+    /// Zooms in around the target of `request`, if it is in the area, or out without a request.
+    /// Returns whether the track is zoomed in.
+    fn set_zoom(&mut self, request: Option<ZoomRequest>, source_kind: &SourceKind, options: &CastOptions) -> bool {
+        let Some(request) = request else {
+            self.zoom.zoom_out();
+            return false;
+        };
+        let center = match request.target {
+            ZoomTarget::Point(x, y) => Some((x as i32, y as i32)),
+            ZoomTarget::Pointer => follow::source_origin(source_kind, self.area.selected_display, &options.display_offsets).and_then(follow::pointer),
+        };
+        let area = &self.area;
+        match center {
+            Some((x, y)) if (area.x as i32..(area.x + area.width) as i32).contains(&x) && (area.y as i32..(area.y + area.height) as i32).contains(&y) => {
+                self.zoom.zoom_in((x as u32, y as u32), request.factor);
+                true
+            }
+            Some(_) => {
+                println!("Track {}, the zoom target is outside the area, no zoom", self.id);
+                false
+            }
+            None => {
+                println!("Track {}, the pointer cannot be read on this source, no zoom", self.id);
+                false
+            }
+        }
+    }
+    /// Drops the source, telling the gui so that the user can pick another one.
+    fn lose(&mut self, error: CaptureError, event_s: &Sender<Event>) {
+        println!("Track {}, capture stopped: {error}", self.id);
+        let _ = event_s.send(Event::SourceLost { track: self.id, error: error.to_string() });
+        self.source = None;
+    }
+}
+
+/// Fits `requested` to the source. If it cannot be cast, `current` is kept, or the whole source
+/// when that does not fit either, and the gui is told which area is actually cast.
+fn fit_area(track: u32, requested: &Area, current: &Area, source: &dyn FrameSource, event_s: &Sender<Event>) -> Area {
+    let (width, height) = (source.width(), source.height());
+    match requested.clamped(width, height) {
+        Ok(area) => area,
+        Err(e) => {
+            println!("Track {track}, area rejected: {e}");
+            let mut area = current.clamped(width, height).unwrap_or_else(|_| Area::new(0, 0, width, height, 0));
+            area.selected_display = requested.selected_display;
+            let _ = event_s.send(Event::AreaRejected { track, error: e.to_string(), area: area.clone() });
+            area
+        }
+    }
+}
+
+/// How to cast, whatever the source.
+#[derive(Debug, Default, Clone)]
+pub struct CastOptions {
+    pub bandwidth_cap: u64, // bit/s, 0 means no cap
+    pub output_size: OutputSize,
+    pub save_option: bool, // also record the frames of the first track to a local session file
+    pub masks: Vec<Mask>, // redacted from every frame, sent or recorded
+    pub overlay: Option<OverlayOptions>, // burnt into every frame, none for no overlay
+    pub follow_mouse: bool, // pan the areas to keep the pointer at their centre
+    pub display_offsets: Vec<(i32, i32)>, // where the displays are, to place the pointer on them
+    pub color_depth: ColorDepth, // of the frames sent; the local recording keeps full colour
+}
+
+/// Casts a track for each of `areas`, all taken from sources of kind `source_kind`.
+pub fn start(ip_addr: String, source_kind: SourceKind, areas: Vec<Area>, options: CastOptions, msg_r: Receiver<Message>, event_s: Sender<Event>) {
     //initialization
     let mut stream;
     match TcpStream::connect(format!("{}:8080", ip_addr)) {
@@ -19,66 +151,214 @@ pub fn start(ip_addr: String, mut area: Area, msg_r: Receiver<Message>) {
         }
     }
     println!("Connection successed");
-    let mut frame_number = 0;
+    // a track without a source keeps the connection, so that the gui can pick another display
+    let mut tracks: Vec<Track> = areas
+        .into_iter()
+        .enumerate()
+        .map(|(i, area)| Track::open(i as u32, &source_kind, area, &event_s))
+        .collect();
+    tracks.iter_mut().for_each(|t| t.follow(&source_kind, &options));
+    let mut transfers = Transfers::new(event_s.clone());
+    let mut rate = RateController::new(options.bandwidth_cap);
+    let mut output_size = options.output_size;
+    let mut masks = options.masks.clone();
+    let mut color_depth = options.color_depth;
+    let overlay = options.overlay.clone().map(|o| {
+        let logo = o.logo.as_deref().and_then(|path| match overlay::load_logo(path) {
+            Ok(logo) => Some(logo),
+            Err(e) => {
+                println!("Impossible loading the logo {}, overlay without it: {e}", path.display());
+                None
+            }
+        });
+        Overlay::new(o, logo)
+    });
+    rate.set_scaling(output_size == OutputSize::FitToBandwidth);
+    let mut stats = StatsRecorder::new();
+    let mut recorder = if options.save_option { start_recording() } else { None };
+    let mut last_position = Duration::MAX;
+    let mut pool = BufferPool::new();
+    let mut palettes = PaletteBuilder::default();
 
-    let mut cpt = capturer::create(area.selected_display);
+    // the receiver only talks back to exchange files, so its packets are read on a separate thread
+    let (packet_s, packet_r) = channel();
+    match stream.try_clone() {
+        Ok(mut s) => {
+            thread::spawn(move || {
+                while let Ok(packet) = read_packet(&mut s) {
+                    if packet_s.send(packet).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Err(e) => println!("Impossible reading from the receiver, file transfer disabled: {e}"),
+    }
 
     // streaming
     'streaming: loop {
-        thread::sleep(capturer::FPS_SLEEP);
+        thread::sleep(rate.frame_interval());
 
         // manage messages from gui
-        if let Ok(msg) = msg_r.try_recv() {
-            match msg.message_type {
+        match msg_r.try_recv() {
+            Ok(msg) => match msg.message_type {
                 MessageType::Stop => {
                     println!("received stop request from gui");
                     break 'streaming;
                 }
                 MessageType::Area => {
-                    area = msg.area;
-                    println!("Selected display: {}", area.selected_display);
-                    cpt = capturer::create(area.selected_display);
+                    tracks.truncate(msg.areas.len());
+                    for (i, area) in msg.areas.into_iter().enumerate() {
+                        match tracks.get_mut(i) {
+                            Some(track) => track.set_area(&source_kind, area, &event_s),
+                            None => tracks.push(Track::open(i as u32, &source_kind, area, &event_s)),
+                        }
+                    }
+                    tracks.iter_mut().for_each(|t| t.follow(&source_kind, &options));
+                }
+                MessageType::Save => {
+                    if !msg.save_option {
+                        // dropping waits for the queued frames to be written, which would stall the cast
+                        if let Some(r) = recorder.take() {
+                            thread::spawn(move || drop(r));
+                        }
+                    } else if recorder.is_none() {
+                        recorder = start_recording();
+                    }
+                }
+                MessageType::Bandwidth => rate.set_cap(msg.bandwidth_cap),
+                MessageType::Output => {
+                    output_size = msg.output_size;
+                    rate.set_scaling(output_size == OutputSize::FitToBandwidth);
+                }
+                MessageType::Seek => {
+                    for s in tracks.iter_mut().filter_map(|t| t.source.as_mut()) {
+                        s.seek(msg.position);
+                    }
                 }
-                _ => {}
+                MessageType::SendFile => transfers.offer(&msg.path),
+                MessageType::AcceptFile => transfers.accept(msg.file_id, &msg.path),
+                MessageType::DeclineFile => transfers.decline(msg.file_id),
+                MessageType::Masks => masks = msg.masks,
+                MessageType::Depth => color_depth = msg.depth,
+                MessageType::Zoom => {
+                    // not short-circuited: every track zooms out, or in if its area has the target
+                    let zoomed = tracks.iter_mut().fold(false, |zoomed, t| t.set_zoom(msg.zoom, &source_kind, &options) | zoomed);
+                    let _ = event_s.send(Event::Zoom(zoomed));
+                }
+                MessageType::Tracks | MessageType::Clip => {}
+            },
+            Err(TryRecvError::Disconnected) if tracks.iter().all(|t| t.source.is_none()) => {
+                println!("No source and nobody left to pick another one");
+                break 'streaming;
             }
+            Err(_) => {}
         }
 
-        frame_number = frame_number + 1;
-
-        let data = capture(&mut cpt);
-        assert_ne!(data.len(), 0, "Capture function returned an empty vector");
-        assert_eq!(data.len(), cpt.width() * cpt.height() * 4, "Dimensions are inconsistent with the captured buffer length.");
-        let frame = Frame::new(cpt.width() as u32, cpt.height() as u32, data);
-        let mut frame = capturer::u8x4_crop(frame,&area);
-        assert_eq!(frame.data.len() as u32, frame.w * frame.h * 4, "Dimensions are inconsistent with the buffer length after crop.");
-        frame.data = capturer::from_bgra_to_rgb(frame.data);
-        assert_eq!(frame.data.len() as u32, frame.w * frame.h * 3, "Dimensions are inconsistent with the buffer length after conversion.");
-
-        // Send header
-        let header = Header::new(frame_number, frame.data.len() as u32, frame.w, frame.h);
-        let encoded_header: Vec<u8> = bincode::serialize(&header).unwrap();
-
-        if let Err(e) = stream.write(&encoded_header) {
-            println!("Connection closed: {}", e);
-            break 'streaming;
+        // manage packets from receiver
+        while let Ok((header, payload)) = packet_r.try_recv() {
+            if header.packet_type != PacketType::Frame {
+                transfers.handle(&header, &payload);
+            }
         }
-        //println!("Header sent {} {}", header.frame_number, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis());
 
-        // Send frame
-        let frame_pad = CHUNK_SIZE - (frame.data.len() as u32 % CHUNK_SIZE);
-        //println!("frame pad {}", frame_pad);
-        if frame_pad < CHUNK_SIZE {
-            for _ in 0..frame_pad {
-                frame.data.push(0);
+        // Send file packets, interleaved with frames
+        for (header, payload) in transfers.outgoing_packets() {
+            let write_start = Instant::now();
+            match write_packet(&mut stream, &header, &payload) {
+                // they share the link with the frames, so they count against the cap
+                Ok(written) => {
+                    if let Some(point) = rate.record(written, write_start.elapsed()) {
+                        let _ = event_s.send(Event::OperatingPoint(point));
+                    }
+                }
+                Err(e) => {
+                    println!("Server closed: {}", e);
+                    break 'streaming;
+                }
             }
         }
-        if let Err(e) = stream.write_all(&frame.data) {
-            println!("Server closed: {}", e);
-            break 'streaming;
-        }
-        if header.frame_number % 10 == 0 {
-            println!("Frame sent {} {}", header.frame_number, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis());
+
+        for track in tracks.iter_mut() {
+            if let (Some(follower), Some(source)) = (track.follower.as_mut(), track.source.as_deref()) {
+                follower.update(&mut track.area, source.width(), source.height());
+            }
+            let frame = match track.source.as_mut().map(|s| s.frame()) {
+                Some(Ok(frame)) => frame,
+                None | Some(Err(CaptureError::Stalled { .. })) => continue,
+                Some(Err(e)) => {
+                    track.lose(e, &event_s);
+                    continue;
+                }
+            };
+            let encode_start = Instant::now();
+            assert_ne!(frame.data.len(), 0, "Capture function returned an empty vector");
+            assert!(frame.is_consistent(), "Dimensions are inconsistent with the captured buffer length.");
+            let view = track.zoom.view(&track.area);
+            let mut frame = capturer::crop_convert(frame, &view, PixelFormat::Rgb, pool.take());
+            assert_eq!(frame.data.len() as u32, frame.w * frame.h * 3, "Dimensions are inconsistent with the buffer length after conversion.");
+            // before scaling, so that a pixelated mask hides as much whatever the output size
+            mask::apply(&mut frame, &view, &masks);
+            // a zoomed view is scaled back to the size of the area, so that the receiver sees no change
+            let capture_size = if view.width != track.area.width || view.height != track.area.height {
+                (track.area.width, track.area.height)
+            } else {
+                (frame.w, frame.h)
+            };
+            let (w, h) = output_size.target(capture_size.0, capture_size.1, rate.scale());
+            if (w, h) != (frame.w, frame.h) {
+                let scaled = capturer::resize(&frame, w, h, FilterType::Triangle);
+                pool.give(mem::replace(&mut frame, scaled).data);
+            }
+            track.frame_number += 1;
+            // after scaling, so that the text is drawn at the size it is seen
+            if let Some(overlay) = &overlay {
+                overlay.draw(&mut frame, track.frame_number);
+            }
+            stats.encode(encode_start.elapsed());
+            if track.id == 0 {
+                if let Some((position, duration)) = track.source.as_ref().and_then(|s| s.position()) {
+                    // a second of precision is enough for the gui
+                    if position.as_secs() != last_position.as_secs() {
+                        let _ = event_s.send(Event::Playback { position, duration });
+                    }
+                    last_position = position;
+                }
+            }
+            if let Some(r) = recorder.as_mut().filter(|_| track.id == 0) {
+                r.record(&frame);
+            }
+
+            // Send frame, with fewer bits per pixel if asked
+            let reduced = (color_depth != ColorDepth::Full).then(|| depth::reduce(&frame, color_depth, pool.take(), &mut palettes));
+            let payload = reduced.as_deref().unwrap_or(&frame.data);
+            let mut header = Header::new(track.id, track.frame_number, payload.len() as u32, frame.w, frame.h);
+            (header.capture_width, header.capture_height) = capture_size;
+            header.depth = color_depth;
+            let write_start = Instant::now();
+            let written = write_packet(&mut stream, &header, payload);
+            pool.give(frame.data);
+            if let Some(reduced) = reduced {
+                pool.give(reduced);
+            }
+            let written = match written {
+                Ok(written) => written,
+                Err(e) => {
+                    println!("Server closed: {}", e);
+                    break 'streaming;
+                }
+            };
+            // the rate is what goes on the wire: header and padding included
+            if let Some(point) = rate.record(written, write_start.elapsed()) {
+                let _ = event_s.send(Event::OperatingPoint(point));
+            }
+            stats.frame(header.len as usize);
+            if let Some(sample) = stats.sample() {
+                let _ = event_s.send(Event::Stats(sample));
+            }
         }
     }
+    // unblocks the thread reading from the receiver
+    let _ = stream.shutdown(Shutdown::Both);
     println!("Sender terminated");
-}
\ No newline at end of file
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// one sample per second, so the gui keeps the last three minutes
+pub const HISTORY_LEN: usize = 180;
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Name, unit and accessor of a metric shown by the gui.
+pub type Metric = (&'static str, &'static str, fn(&Sample) -> f32);
+pub const METRICS: [Metric; 8] = [
+    ("Fps", "", |s| s.fps),
+    ("Bitrate", "Mbit/s", |s| s.bitrate),
+    ("Frame size", "KB", |s| s.frame_size),
+    ("Encode time", "ms", |s| s.encode_ms),
+    ("Decode time", "ms", |s| s.decode_ms),
+    ("Latency", "ms", |s| s.latency_ms),
+    ("Dropped frames", "", |s| s.dropped as f32),
+    ("Reconnects", "", |s| s.reconnects as f32),
+];
+
+/// Statistics of one side of the stream over one sample period.
+/// Times are averages in milliseconds, counters are totals for the period except `reconnects`,
+/// which counts since the session started.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sample {
+    pub fps: f32,
+    pub bitrate: f32, // Mbit/s
+    pub frame_size: f32, // KB
+    pub encode_ms: f32,
+    pub decode_ms: f32,
+    pub latency_ms: f32,
+    pub dropped: u32,
+    pub reconnects: u32,
+}
+
+/// Collects the measures fed by the sender or the receiver and turns them into a `Sample`
+/// once per period.
+pub struct StatsRecorder {
+    window_start: Instant,
+    frames: u32,
+    bytes: u64,
+    encode: Duration,
+    decode: Duration,
+    latency: Duration,
+    dropped: u32,
+    reconnects: u32,
+}
+
+impl StatsRecorder {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            frames: 0,
+            bytes: 0,
+            encode: Duration::ZERO,
+            decode: Duration::ZERO,
+            latency: Duration::ZERO,
+            dropped: 0,
+            reconnects: 0,
+        }
+    }
+    pub fn frame(&mut self, bytes: usize) {
+        self.frames += 1;
+        self.bytes += bytes as u64;
+    }
+    pub fn encode(&mut self, time: Duration) {
+        self.encode += time;
+    }
+    pub fn decode(&mut self, time: Duration) {
+        self.decode += time;
+    }
+    pub fn latency(&mut self, time: Duration) {
+        self.latency += time;
+    }
+    pub fn dropped(&mut self, frames: u32) {
+        self.dropped += frames;
+    }
+    pub fn reconnect(&mut self) {
+        self.reconnects += 1;
+    }
+    /// Returns the sample of the period just ended, if any.
+    pub fn sample(&mut self) -> Option<Sample> {
+        self.sample_at(Instant::now())
+    }
+    // `sample` at `now`
+    fn sample_at(&mut self, now: Instant) -> Option<Sample> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < SAMPLE_PERIOD {
+            return None;
+        }
+        let seconds = elapsed.as_secs_f32();
+        let frames = self.frames.max(1) as f32;
+        let sample = Sample {
+            fps: self.frames as f32 / seconds,
+            bitrate: self.bytes as f32 * 8.0 / seconds / 1_000_000.0,
+            frame_size: self.bytes as f32 / frames / 1000.0,
+            encode_ms: self.encode.as_secs_f32() * 1000.0 / frames,
+            decode_ms: self.decode.as_secs_f32() * 1000.0 / frames,
+            latency_ms: self.latency.as_secs_f32() * 1000.0 / frames,
+            dropped: self.dropped,
+            reconnects: self.reconnects,
+        };
+        *self = Self { window_start: now, reconnects: self.reconnects, ..Self::new() };
+        Some(sample)
+    }
+}
+impl Default for StatsRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The samples shown by the gui, oldest first.
+#[derive(Default)]
+pub struct StatsHistory {
+    samples: VecDeque<Sample>,
+}
+
+impl StatsHistory {
+    pub fn push(&mut self, sample: Sample) {
+        if self.samples.len() == HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+    pub fn last(&self) -> Option<&Sample> {
+        self.samples.back()
+    }
+    pub fn series(&self, metric: impl Fn(&Sample) -> f32) -> Vec<f32> {
+        self.samples.iter().map(metric).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    fn recorder() -> (StatsRecorder, Instant) {
+        let mut stats = StatsRecorder::new();
+        let now = Instant::now();
+        stats.window_start = now;
+        (stats, now)
+    }
+
+    #[test]
+    fn samples_average_the_period() {
+        let (mut stats, now) = recorder();
+        for _ in 0..30 {
+            stats.frame(10_000);
+            stats.decode(Duration::from_millis(2));
+            stats.latency(Duration::from_millis(20));
+        }
+        stats.dropped(3);
+        // nothing before the end of the period
+        assert!(stats.sample_at(now + SAMPLE_PERIOD / 2).is_none());
+        let sample = stats.sample_at(now + SAMPLE_PERIOD).unwrap();
+        assert!(close(sample.fps, 30.0) && close(sample.bitrate, 2.4) && close(sample.frame_size, 10.0), "{sample:?}");
+        assert!(close(sample.decode_ms, 2.0) && close(sample.latency_ms, 20.0) && close(sample.encode_ms, 0.0), "{sample:?}");
+        assert_eq!(sample.dropped, 3);
+
+        // the next period starts afresh, and a period without frames divides by nothing
+        let sample = stats.sample_at(now + SAMPLE_PERIOD * 3).unwrap();
+        assert!(close(sample.fps, 0.0) && close(sample.frame_size, 0.0) && close(sample.latency_ms, 0.0), "{sample:?}");
+        assert_eq!(sample.dropped, 0);
+    }
+
+    #[test]
+    fn reconnects_count_for_the_whole_session() {
+        let (mut stats, now) = recorder();
+        stats.reconnect();
+        stats.reconnect();
+        assert_eq!(stats.sample_at(now + SAMPLE_PERIOD).unwrap().reconnects, 2);
+        stats.reconnect();
+        assert_eq!(stats.sample_at(now + SAMPLE_PERIOD * 2).unwrap().reconnects, 3);
+    }
+
+    #[test]
+    fn history_keeps_the_last_samples() {
+        let mut history = StatsHistory::default();
+        assert!(history.last().is_none());
+        for i in 0..HISTORY_LEN + 5 {
+            history.push(Sample { dropped: i as u32, ..Sample::default() });
+        }
+        let dropped = history.series(|s| s.dropped as f32);
+        assert_eq!(dropped.len(), HISTORY_LEN);
+        assert_eq!((dropped[0], history.last().unwrap().dropped), (5.0, HISTORY_LEN as u32 + 4));
+        history.clear();
+        assert!(history.series(|s| s.fps).is_empty());
+    }
+}
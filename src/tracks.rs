@@ -0,0 +1,131 @@
+use crate::capturer::{Frame, PixelFormat};
+
+/// How the receiver shows the tracks of a cast.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TrackLayout {
+    #[default]
+    SideBySide,
+    Single(u32),
+}
+
+/// How the receiver saves a cast with several tracks.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RecordMode {
+    /// One video with the tracks side by side.
+    #[default]
+    Composited,
+    /// One video per track.
+    Separate,
+}
+
+/// The latest RGB frame of each track received so far, with the frame numbers needed to count
+/// the frames dropped on each of them.
+#[derive(Default)]
+pub struct TrackSet {
+    frames: Vec<Option<Frame>>,
+    last_numbers: Vec<u32>,
+}
+
+impl TrackSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> u32 {
+        self.frames.len() as u32
+    }
+    /// Forgets the frame numbers, e.g. when the sender reconnects and numbers from 1 again.
+    /// The frames are kept, so that the view does not go blank meanwhile.
+    pub fn reset_numbers(&mut self) {
+        self.last_numbers.iter_mut().for_each(|n| *n = 0);
+    }
+    /// Stores the frame number `frame_number` of `track`, returning how many frames of that track
+    /// went missing before it. The frame is converted to RGB if it is in another format.
+    ///
+    /// `track` must be below `MAX_TRACKS`, which `read_packet` checks.
+    pub fn update(&mut self, track: u32, frame_number: u32, frame: Frame) -> u32 {
+        let i = track as usize;
+        if i >= self.frames.len() {
+            self.frames.resize_with(i + 1, || None);
+            self.last_numbers.resize(i + 1, 0);
+        }
+        let dropped = frame_number.saturating_sub(self.last_numbers[i].saturating_add(1));
+        self.last_numbers[i] = frame_number;
+        self.frames[i] = Some(frame.into_format(PixelFormat::Rgb));
+        dropped
+    }
+    pub fn frame(&self, track: u32) -> Option<&Frame> {
+        self.frames.get(track as usize)?.as_ref()
+    }
+    /// Builds what `layout` shows after `track` changed, or `None` if that change is not visible.
+    pub fn view(&self, layout: TrackLayout, track: u32) -> Option<Frame> {
+        match layout {
+            TrackLayout::Single(t) if t == track || (t >= self.len() && track == 0) => self.frame(track).cloned(),
+            TrackLayout::Single(_) => None,
+            TrackLayout::SideBySide => Some(self.composite()),
+        }
+    }
+    /// Puts the latest frame of every track side by side, top aligned, on a black background.
+    pub fn composite(&self) -> Frame {
+        let frames: Vec<&Frame> = self.frames.iter().flatten().collect();
+        if let [frame] = frames[..] {
+            return frame.clone();
+        }
+        let w: u32 = frames.iter().map(|f| f.w).sum();
+        let h = frames.iter().map(|f| f.h).max().unwrap_or(0);
+        let mut data = vec![0; (w * h * 3) as usize];
+        let mut left = 0;
+        for frame in frames {
+            for y in 0..frame.h {
+                let start = ((y * w + left) * 3) as usize;
+                data[start..start + (frame.w * 3) as usize].copy_from_slice(frame.row(y));
+            }
+            left += frame.w;
+        }
+        Frame::new(w, h, PixelFormat::Rgb, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(w: u32, h: u32, value: u8) -> Frame {
+        Frame::new(w, h, PixelFormat::Rgb, vec![value; (w * h * 3) as usize])
+    }
+
+    #[test]
+    fn tracks_are_composited_side_by_side() {
+        let mut tracks = TrackSet::new();
+        tracks.update(0, 1, plain(2, 2, 10));
+        tracks.update(1, 1, plain(3, 1, 20));
+        let frame = tracks.composite();
+        assert_eq!((frame.w, frame.h), (5, 2));
+        assert_eq!(frame.row(0), [10, 10, 10, 10, 10, 10, 20, 20, 20, 20, 20, 20, 20, 20, 20]);
+        // the shorter track leaves black below it
+        assert_eq!(frame.row(1), [10, 10, 10, 10, 10, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn drops_are_counted_per_track() {
+        let mut tracks = TrackSet::new();
+        assert_eq!(tracks.update(0, 1, plain(1, 1, 0)), 0);
+        assert_eq!(tracks.update(1, 1, plain(1, 1, 0)), 0);
+        assert_eq!(tracks.update(0, 2, plain(1, 1, 0)), 0);
+        assert_eq!(tracks.update(1, 4, plain(1, 1, 0)), 2);
+        tracks.reset_numbers();
+        assert_eq!(tracks.update(0, 1, plain(1, 1, 0)), 0);
+        // the peer's numbers are not trusted to stay below the maximum
+        assert_eq!(tracks.update(0, u32::MAX, plain(1, 1, 0)), u32::MAX - 2);
+        assert_eq!(tracks.update(0, u32::MAX, plain(1, 1, 0)), 0);
+    }
+
+    #[test]
+    fn a_single_track_is_shown_only_when_it_changes() {
+        let mut tracks = TrackSet::new();
+        tracks.update(0, 1, plain(1, 1, 0));
+        tracks.update(1, 1, plain(1, 1, 0));
+        assert!(tracks.view(TrackLayout::Single(1), 0).is_none());
+        assert!(tracks.view(TrackLayout::Single(1), 1).is_some());
+        assert!(tracks.view(TrackLayout::SideBySide, 0).is_some());
+    }
+}
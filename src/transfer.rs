@@ -0,0 +1,495 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use serde::{Deserialize, Serialize};
+use crate::util::{Event, Header, PacketType, CHUNK_SIZE};
+
+// a chunk and its bincode envelope must fit in a single CHUNK_SIZE block
+const FILE_CHUNK_SIZE: usize = CHUNK_SIZE as usize - 64;
+// chunks sent for each frame, so that a transfer never stalls the streaming
+const CHUNKS_PER_FRAME: usize = 4;
+// offers waiting for the user to answer; more are declined without asking
+const MAX_PENDING_OFFERS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileOffer {
+    pub id: u32,
+    pub name: String,
+    pub size: u64,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct FileReply {
+    id: u32,
+    accepted: bool,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct FileChunk {
+    id: u32,
+    data: Vec<u8>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct FileEnd {
+    id: u32,
+    checksum: u32,
+}
+// the receiver's verdict on a transfer, once it ended or as soon as it failed
+#[derive(Serialize, Deserialize, Debug)]
+struct FileResult {
+    id: u32,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferState {
+    Waiting,
+    Active,
+    Completed(PathBuf),
+    Declined,
+    Failed(String),
+}
+#[derive(Debug, Clone)]
+pub struct TransferStatus {
+    pub id: u32,
+    pub name: String,
+    pub outgoing: bool,
+    pub done: u64,
+    pub total: u64,
+    pub state: TransferState,
+}
+
+struct Outgoing {
+    offer: FileOffer,
+    file: File,
+    sent: u64,
+    accepted: bool,
+    ended: bool, // every chunk was sent, the peer is verifying them
+    hasher: crc32fast::Hasher,
+}
+struct Incoming {
+    offer: FileOffer,
+    file: Option<File>,
+    part: PathBuf,
+    dest: PathBuf,
+    received: u64,
+    hasher: crc32fast::Hasher,
+}
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        // an unfinished download leaves nothing behind; after the rename this is a no-op.
+        // The file is closed first, as Windows refuses to remove an open file.
+        self.file.take();
+        let _ = fs::remove_file(&self.part);
+    }
+}
+
+/// Files exchanged with the peer on the session connection.
+/// Both sides own one: outgoing transfers are offered, then sent chunk by chunk once the peer
+/// accepts; incoming transfers are written to the download directory and verified on completion,
+/// which the peer is told about.
+pub struct Transfers {
+    event_s: Sender<Event>,
+    next_id: u32,
+    outgoing: HashMap<u32, Outgoing>,
+    offered: HashMap<u32, FileOffer>,
+    incoming: HashMap<u32, Incoming>,
+    control: VecDeque<(Header, Vec<u8>)>,
+}
+
+impl Transfers {
+    pub fn new(event_s: Sender<Event>) -> Self {
+        Self {
+            event_s,
+            next_id: 0,
+            outgoing: HashMap::new(),
+            offered: HashMap::new(),
+            incoming: HashMap::new(),
+            control: VecDeque::new(),
+        }
+    }
+    pub fn offer(&mut self, path: &Path) {
+        self.next_id += 1;
+        let id = self.next_id;
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let file = match File::open(path).and_then(|f| f.metadata().map(|m| (f, m))) {
+            Ok((f, m)) if m.is_file() => (f, m.len()),
+            Ok(_) => {
+                self.notify_failure(id, name, true, "not a regular file".to_string());
+                return;
+            }
+            Err(e) => {
+                self.notify_failure(id, name, true, e.to_string());
+                return;
+            }
+        };
+        let offer = FileOffer { id, name, size: file.1 };
+        self.queue(PacketType::FileOffer, &offer);
+        self.notify(&offer, true, 0, TransferState::Waiting);
+        self.outgoing.insert(offer.id, Outgoing {
+            offer,
+            file: file.0,
+            sent: 0,
+            accepted: false,
+            ended: false,
+            hasher: crc32fast::Hasher::new(),
+        });
+    }
+    pub fn accept(&mut self, id: u32, download_dir: &Path) {
+        let Some(offer) = self.offered.remove(&id) else { return; };
+        let dest = unique_path(download_dir, &offer.name);
+        let mut part = dest.clone().into_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        match fs::create_dir_all(download_dir).and_then(|_| File::create(&part)) {
+            Ok(file) => {
+                self.queue(PacketType::FileReply, &FileReply { id, accepted: true });
+                self.notify(&offer, false, 0, TransferState::Active);
+                self.incoming.insert(id, Incoming { offer, file: Some(file), part, dest, received: 0, hasher: crc32fast::Hasher::new() });
+            }
+            Err(e) => {
+                eprintln!("Impossible creating {}: {e}", part.display());
+                self.queue(PacketType::FileReply, &FileReply { id, accepted: false });
+                self.notify_failure(id, offer.name, false, e.to_string());
+            }
+        }
+    }
+    pub fn decline(&mut self, id: u32) {
+        if let Some(offer) = self.offered.remove(&id) {
+            self.queue(PacketType::FileReply, &FileReply { id, accepted: false });
+            self.notify(&offer, false, 0, TransferState::Declined);
+        }
+    }
+
+    /// Handles a file packet received from the peer.
+    pub fn handle(&mut self, header: &Header, payload: &[u8]) {
+        match header.packet_type {
+            PacketType::FileOffer => {
+                if let Ok(offer) = bincode::deserialize::<FileOffer>(payload) {
+                    if self.offered.contains_key(&offer.id) || self.incoming.contains_key(&offer.id) {
+                        return;
+                    }
+                    if self.offered.len() >= MAX_PENDING_OFFERS {
+                        eprintln!("Too many files offered, {} declined", offer.name);
+                        self.queue(PacketType::FileReply, &FileReply { id: offer.id, accepted: false });
+                        return;
+                    }
+                    self.notify(&offer, false, 0, TransferState::Waiting);
+                    let _ = self.event_s.send(Event::FileOffered(offer.clone()));
+                    self.offered.insert(offer.id, offer);
+                }
+            }
+            PacketType::FileReply => {
+                if let Ok(reply) = bincode::deserialize::<FileReply>(payload) {
+                    if reply.accepted {
+                        if let Some(out) = self.outgoing.get_mut(&reply.id) {
+                            out.accepted = true;
+                            let offer = out.offer.clone();
+                            self.notify(&offer, true, 0, TransferState::Active);
+                        }
+                    } else if let Some(out) = self.outgoing.remove(&reply.id) {
+                        self.notify(&out.offer, true, 0, TransferState::Declined);
+                    }
+                }
+            }
+            PacketType::FileChunk => {
+                if let Ok(chunk) = bincode::deserialize::<FileChunk>(payload) {
+                    self.receive_chunk(chunk);
+                }
+            }
+            PacketType::FileEnd => {
+                if let Ok(end) = bincode::deserialize::<FileEnd>(payload) {
+                    self.finish(end);
+                }
+            }
+            PacketType::FileResult => {
+                if let Ok(result) = bincode::deserialize::<FileResult>(payload) {
+                    if let Some(out) = self.outgoing.remove(&result.id) {
+                        let state = match result.error {
+                            None => TransferState::Completed(PathBuf::from(&out.offer.name)),
+                            Some(e) => TransferState::Failed(e),
+                        };
+                        self.notify(&out.offer, true, out.sent, state);
+                    }
+                }
+            }
+            PacketType::Frame => {}
+        }
+    }
+
+    /// Returns the packets to send to the peer now: pending replies and offers, plus a few
+    /// chunks of every accepted outgoing transfer. A transfer is complete once the peer verified it.
+    pub fn outgoing_packets(&mut self) -> Vec<(Header, Vec<u8>)> {
+        let mut packets: Vec<(Header, Vec<u8>)> = self.control.drain(..).collect();
+        let mut finished = Vec::new();
+        for out in self.outgoing.values_mut().filter(|o| o.accepted && !o.ended) {
+            for _ in 0..CHUNKS_PER_FRAME {
+                let mut data = vec![0; FILE_CHUNK_SIZE];
+                let n = match out.file.read(&mut data) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        eprintln!("Impossible reading {}: {e}", out.offer.name);
+                        0
+                    }
+                };
+                if n == 0 {
+                    finished.push(out.offer.id);
+                    break;
+                }
+                data.truncate(n);
+                out.hasher.update(&data);
+                out.sent += n as u64;
+                packets.push(encode(PacketType::FileChunk, &FileChunk { id: out.offer.id, data }));
+            }
+            let _ = self.event_s.send(Event::Transfer(status(&out.offer, true, out.sent, TransferState::Active)));
+        }
+        for id in finished {
+            let Some(out) = self.outgoing.get_mut(&id) else { continue; };
+            let checksum = out.hasher.clone().finalize();
+            packets.push(encode(PacketType::FileEnd, &FileEnd { id, checksum }));
+            if out.sent == out.offer.size {
+                out.ended = true;
+            } else if let Some(out) = self.outgoing.remove(&id) {
+                // the peer fails it too, as the size does not match
+                self.notify(&out.offer, true, out.sent, TransferState::Failed("file changed while sending".to_string()));
+            }
+        }
+        packets
+    }
+
+    fn receive_chunk(&mut self, chunk: FileChunk) {
+        let Some(inc) = self.incoming.get_mut(&chunk.id) else { return; };
+        // nothing more than the size offered is written, whatever the peer sends
+        if inc.received + chunk.data.len() as u64 > inc.offer.size {
+            self.fail(chunk.id, "more data than offered".to_string());
+            return;
+        }
+        let Some(file) = inc.file.as_mut() else { return; };
+        if let Err(e) = file.write_all(&chunk.data) {
+            eprintln!("Impossible writing {}: {e}", inc.part.display());
+            self.fail(chunk.id, e.to_string());
+            return;
+        }
+        inc.hasher.update(&chunk.data);
+        inc.received += chunk.data.len() as u64;
+        let _ = self.event_s.send(Event::Transfer(status(&inc.offer, false, inc.received, TransferState::Active)));
+    }
+    fn finish(&mut self, end: FileEnd) {
+        let Some(mut inc) = self.incoming.remove(&end.id) else { return; };
+        let synced = inc.file.take().map_or(Ok(()), |f| f.sync_all());
+        let state = if inc.received != inc.offer.size || inc.hasher.clone().finalize() != end.checksum {
+            TransferState::Failed("integrity check failed".to_string())
+        } else if let Err(e) = synced.and_then(|_| fs::rename(&inc.part, &inc.dest)) {
+            TransferState::Failed(e.to_string())
+        } else {
+            eprintln!("File received: {}", inc.dest.display());
+            TransferState::Completed(inc.dest.clone())
+        };
+        let error = match &state {
+            TransferState::Failed(e) => Some(e.clone()),
+            _ => None,
+        };
+        self.queue(PacketType::FileResult, &FileResult { id: end.id, error });
+        self.notify(&inc.offer, false, inc.received, state);
+    }
+    // stops an incoming transfer before its end, telling the peer to stop sending it
+    fn fail(&mut self, id: u32, error: String) {
+        if let Some(inc) = self.incoming.remove(&id) {
+            self.queue(PacketType::FileResult, &FileResult { id, error: Some(error.clone()) });
+            self.notify(&inc.offer, false, inc.received, TransferState::Failed(error));
+        }
+    }
+
+    fn queue<T: Serialize>(&mut self, packet_type: PacketType, payload: &T) {
+        self.control.push_back(encode(packet_type, payload));
+    }
+    fn notify(&self, offer: &FileOffer, outgoing: bool, done: u64, state: TransferState) {
+        let _ = self.event_s.send(Event::Transfer(status(offer, outgoing, done, state)));
+    }
+    fn notify_failure(&self, id: u32, name: String, outgoing: bool, error: String) {
+        let offer = FileOffer { id, name, size: 0 };
+        self.notify(&offer, outgoing, 0, TransferState::Failed(error));
+    }
+}
+
+fn encode<T: Serialize>(packet_type: PacketType, payload: &T) -> (Header, Vec<u8>) {
+    let data = bincode::serialize(payload).unwrap();
+    (Header::control(packet_type, data.len() as u32), data)
+}
+fn status(offer: &FileOffer, outgoing: bool, done: u64, state: TransferState) -> TransferStatus {
+    TransferStatus { id: offer.id, name: offer.name.clone(), outgoing, done, total: offer.size, state }
+}
+/// Joins the offered name to the download directory, never escaping it nor overwriting a file.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name).file_name().map(|n| n.to_os_string()).unwrap_or_else(|| "download".into());
+    let mut path = dir.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        let stem = Path::new(&name).file_stem().unwrap_or_default().to_string_lossy();
+        path = match Path::new(&name).extension() {
+            Some(ext) => dir.join(format!("{stem} ({n}).{}", ext.to_string_lossy())),
+            None => dir.join(format!("{stem} ({n})")),
+        };
+        n += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc::{channel, Receiver};
+    use crate::util::{read_packet, write_packet};
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("transfer_test_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // sends the packets of `from` to `to` over an in-memory stream, `tamper`ed with on the way
+    fn exchange(from: &mut Transfers, to: &mut Transfers, tamper: impl Fn(&Header, &mut Vec<u8>)) {
+        let mut stream = Vec::new();
+        for (header, mut payload) in from.outgoing_packets() {
+            tamper(&header, &mut payload);
+            write_packet(&mut stream, &header, &payload).unwrap();
+        }
+        let len = stream.len() as u64;
+        let mut stream = Cursor::new(stream);
+        while stream.position() < len {
+            let (header, payload) = read_packet(&mut stream).unwrap();
+            to.handle(&header, &payload);
+        }
+    }
+
+    fn states(event_r: &Receiver<Event>) -> Vec<TransferState> {
+        event_r.try_iter().filter_map(|e| if let Event::Transfer(status) = e { Some(status.state) } else { None }).collect()
+    }
+
+    // offers a file of `size` bytes from a sender to a receiver, which accepts it into `dir` when
+    // `accept` is set; returns the events of both sides
+    fn transfer(dir: &Path, size: usize, accept: bool, tamper: impl Fn(&Header, &mut Vec<u8>)) -> (Vec<TransferState>, Vec<TransferState>) {
+        let source = dir.join("source.bin");
+        fs::write(&source, (0..size).map(|i| i as u8).collect::<Vec<u8>>()).unwrap();
+        let (sender_s, sender_r) = channel();
+        let (receiver_s, receiver_r) = channel();
+        let (mut sender, mut receiver) = (Transfers::new(sender_s), Transfers::new(receiver_s));
+
+        sender.offer(&source);
+        exchange(&mut sender, &mut receiver, |_, _| {});
+        let offer = receiver_r.try_iter().find_map(|e| if let Event::FileOffered(offer) = e { Some(offer) } else { None }).unwrap();
+        assert_eq!((offer.name.as_str(), offer.size), ("source.bin", size as u64));
+        if accept {
+            receiver.accept(offer.id, &dir.join("downloads"));
+        } else {
+            receiver.decline(offer.id);
+        }
+        exchange(&mut receiver, &mut sender, |_, _| {});
+        // a few chunks are sent each time, until the end of the file
+        for _ in 0..size / (FILE_CHUNK_SIZE * CHUNKS_PER_FRAME) + 2 {
+            exchange(&mut sender, &mut receiver, &tamper);
+        }
+        // the sender learns whether the file passed the checks
+        exchange(&mut receiver, &mut sender, |_, _| {});
+        (states(&sender_r), states(&receiver_r))
+    }
+
+    #[test]
+    fn accepted_files_are_received_whole() {
+        let dir = dir("accepted");
+        let size = FILE_CHUNK_SIZE * 5 + 123;
+        let (sent, received) = transfer(&dir, size, true, |_, _| {});
+        let dest = dir.join("downloads").join("source.bin");
+        assert_eq!(sent.last(), Some(&TransferState::Completed(PathBuf::from("source.bin"))));
+        assert_eq!(received.last(), Some(&TransferState::Completed(dest.clone())));
+        assert_eq!(fs::read(&dest).unwrap(), fs::read(dir.join("source.bin")).unwrap());
+        assert!(!dir.join("downloads").join("source.bin.part").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_files_fail_the_integrity_check() {
+        let dir = dir("corrupted");
+        // the last byte of the first chunk is flipped on the way
+        let (sent, received) = transfer(&dir, FILE_CHUNK_SIZE * 2, true, |header, payload| {
+            if header.packet_type == PacketType::FileChunk && payload[payload.len() - 1] == (FILE_CHUNK_SIZE - 1) as u8 {
+                *payload.last_mut().unwrap() ^= 0xFF;
+            }
+        });
+        assert_eq!(received.last(), Some(&TransferState::Failed("integrity check failed".to_string())));
+        assert_eq!(sent.last(), received.last());
+        // neither the file nor its partial download is left behind
+        assert_eq!(fs::read_dir(dir.join("downloads")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_longer_than_offered_fail() {
+        let dir = dir("longer");
+        // the file grows after it is offered
+        let source = dir.join("source.bin");
+        fs::write(&source, vec![1; 1000]).unwrap();
+        let (sender_s, sender_r) = channel();
+        let (receiver_s, receiver_r) = channel();
+        let (mut sender, mut receiver) = (Transfers::new(sender_s), Transfers::new(receiver_s));
+        sender.offer(&source);
+        exchange(&mut sender, &mut receiver, |_, _| {});
+        receiver.accept(1, &dir.join("downloads"));
+        exchange(&mut receiver, &mut sender, |_, _| {});
+        fs::write(&source, vec![1; FILE_CHUNK_SIZE * CHUNKS_PER_FRAME * 2]).unwrap();
+        exchange(&mut sender, &mut receiver, |_, _| {});
+
+        let failed = TransferState::Failed("more data than offered".to_string());
+        assert_eq!(states(&receiver_r).last(), Some(&failed));
+        assert_eq!(fs::read_dir(dir.join("downloads")).unwrap().count(), 0);
+        // the sender is told, and stops sending
+        exchange(&mut receiver, &mut sender, |_, _| {});
+        assert_eq!(states(&sender_r).last(), Some(&failed));
+        assert!(sender.outgoing_packets().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pending_offers_are_limited() {
+        let (event_s, event_r) = channel();
+        let mut receiver = Transfers::new(event_s);
+        // every offer twice
+        for id in (0..MAX_PENDING_OFFERS as u32 + 2).flat_map(|id| [id, id]) {
+            let (header, payload) = encode(PacketType::FileOffer, &FileOffer { id, name: format!("{id}.bin"), size: 10 });
+            receiver.handle(&header, &payload);
+        }
+        let prompts = event_r.try_iter().filter(|e| matches!(e, Event::FileOffered(_))).count();
+        assert_eq!((prompts, receiver.offered.len()), (MAX_PENDING_OFFERS, MAX_PENDING_OFFERS));
+        // the others are declined at once, each time they are offered
+        let replies: Vec<FileReply> = receiver.outgoing_packets().iter().map(|(_, p)| bincode::deserialize(p).unwrap()).collect();
+        assert_eq!(replies.len(), 4);
+        assert!(replies.iter().all(|r| !r.accepted && r.id >= MAX_PENDING_OFFERS as u32));
+    }
+
+    #[test]
+    fn declined_files_are_not_sent() {
+        let dir = dir("declined");
+        let (sent, received) = transfer(&dir, 1000, false, |_, _| {});
+        assert_eq!(sent.last(), Some(&TransferState::Declined));
+        assert_eq!(received.last(), Some(&TransferState::Declined));
+        assert!(!dir.join("downloads").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn downloads_never_overwrite_a_file() {
+        let dir = dir("unique");
+        assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes.txt"));
+        fs::write(dir.join("notes.txt"), "kept").unwrap();
+        fs::write(dir.join("notes (1).txt"), "kept").unwrap();
+        assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes (2).txt"));
+        fs::write(dir.join("README"), "kept").unwrap();
+        assert_eq!(unique_path(&dir, "README"), dir.join("README (1)"));
+        // a name cannot escape the download directory
+        assert_eq!(unique_path(&dir, "../../etc/passwd"), dir.join("passwd"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,33 +1,189 @@
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
 use serde::{Deserialize, Serialize};
-use crate::capturer::Area;
+use crate::bandwidth::OperatingPoint;
+use crate::capturer::{Area, OutputSize};
+use crate::clip::GifOptions;
+use crate::depth::ColorDepth;
+use crate::mask::Mask;
+use crate::zoom::ZoomRequest;
+use crate::stats::Sample;
+use crate::tracks::{RecordMode, TrackLayout};
+use crate::transfer::{FileOffer, TransferStatus};
 
 pub const CHUNK_SIZE: u32 = 64 * 1024; //65536
+/// Largest payload a packet may announce: an RGB frame of 8K fits, and a file chunk far more so.
+pub const MAX_PAYLOAD: u32 = 128 * 1024 * 1024;
+/// Most tracks a cast may have; the receiver keeps a slot for each track number up to the highest.
+pub const MAX_TRACKS: u32 = 16;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum PacketType {
+    #[default]
+    Frame,
+    FileOffer,
+    FileReply,
+    FileChunk,
+    FileEnd,
+    FileResult,
+}
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Header {
+    pub packet_type: PacketType,
+    pub track: u32, // frames of each track are numbered separately
     pub frame_number: u32,
     pub len: u32,
     pub frame_width: u32,
     pub frame_height: u32,
+    // size of the area captured, before it was scaled to the frame size
+    pub capture_width: u32,
+    pub capture_height: u32,
+    pub timestamp: u64, // ms since UNIX epoch, to measure latency
+    pub depth: ColorDepth, // of the payload, expanded back to RGB by the receiver
 }
 impl Header {
-    pub fn new(frame_number: u32, len: u32, image_width: u32, image_height: u32) -> Self {
-        Self { frame_number, len, frame_width: image_width, frame_height: image_height }
+    pub fn new(track: u32, frame_number: u32, len: u32, image_width: u32, image_height: u32) -> Self {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        Self {
+            packet_type: PacketType::Frame,
+            track,
+            frame_number,
+            len,
+            frame_width: image_width,
+            frame_height: image_height,
+            capture_width: image_width,
+            capture_height: image_height,
+            timestamp,
+            depth: ColorDepth::Full,
+        }
+    }
+    /// Time elapsed since the header was created, assuming both peers have synchronized clocks.
+    pub fn age(&self) -> Duration {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.timestamp))
+    }
+    pub fn control(packet_type: PacketType, len: u32) -> Self {
+        Self { packet_type, len, ..Default::default() }
+    }
+}
+
+/// Size of an encoded header. Every field is fixed-size, so this holds for any header.
+pub fn header_size() -> usize {
+    bincode::serialized_size(&Header::default()).unwrap() as usize
+}
+
+static PADDING: [u8; CHUNK_SIZE as usize] = [0; CHUNK_SIZE as usize];
+
+/// Writes a header followed by its payload, padded to a multiple of CHUNK_SIZE, and returns the
+/// number of bytes written. The payload is borrowed, so that the caller can reuse its buffer.
+pub fn write_packet(stream: &mut impl Write, header: &Header, data: &[u8]) -> io::Result<usize> {
+    let encoded_header = bincode::serialize(header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    stream.write_all(&encoded_header)?;
+    stream.write_all(data)?;
+
+    let frame_pad = CHUNK_SIZE - (data.len() as u32 % CHUNK_SIZE);
+    if frame_pad < CHUNK_SIZE {
+        stream.write_all(&PADDING[..frame_pad as usize])?;
+    }
+    Ok(encoded_header.len() + data.len() + (frame_pad % CHUNK_SIZE) as usize)
+}
+
+/// Reads a packet written by `write_packet`, returning the header and the payload without padding.
+pub fn read_packet(stream: &mut impl Read) -> io::Result<(Header, Vec<u8>)> {
+    let mut header_buffer = vec![0; header_size()];
+    stream.read_exact(&mut header_buffer)?;
+    let header: Header = bincode::deserialize(&header_buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    // the length comes from the peer: checked before anything is allocated for it
+    if header.len > MAX_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("packet of {} bytes, above the maximum", header.len)));
     }
+    if header.track >= MAX_TRACKS {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("packet of track {}, above the maximum", header.track)));
+    }
+    let len = header.len as usize;
+    let mut data = vec![0; len.div_ceil(CHUNK_SIZE as usize) * CHUNK_SIZE as usize];
+    stream.read_exact(&mut data)?;
+    data.truncate(len);
+    Ok((header, data))
+}
+
+/// `secs` since the epoch as the UTC year, month, day, hour, minute and second.
+pub fn utc_date_time(secs: u64) -> [u64; 6] {
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // civil from days, after Howard Hinnant: eras of 400 years starting on the 1st of March
+    let z = days + 719_468;
+    let (era, doe) = (z / 146_097, z % 146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + u64::from(month <= 2);
+    [year, month, day, secs / 3600, secs / 60 % 60, secs % 60]
+}
+
+/// Notifications sent by the sender and receiver threads to the gui.
+pub enum Event {
+    FileOffered(FileOffer),
+    Transfer(TransferStatus),
+    OperatingPoint(OperatingPoint),
+    Stats(Sample),
+    Playback { position: Duration, duration: Duration },
+    /// The source of a track stopped producing frames; the sender waits for other areas to be applied.
+    SourceLost { track: u32, error: String },
+    /// The area requested for a track did not fit its source; the sender kept casting the one it reports.
+    AreaRejected { track: u32, error: String, area: Area },
+    /// The receiver got frames from this many tracks.
+    Tracks(u32),
+    /// The size of the frames of a track changed: as received, and as captured before scaling.
+    Resolution { track: u32, output: (u32, u32), capture: (u32, u32) },
+    /// The receiver exported the clip asked for: where, and how many frames it has.
+    ClipExported(Result<(PathBuf, usize), String>),
+    /// The sender applied a zoom request: whether a track is zoomed in, none if the target of a
+    /// zoom-in was outside every area or could not be read.
+    Zoom(bool),
 }
+
 #[derive(Default)]
 pub enum MessageType {
     #[default]
     Stop,
     Area,
     Save,
+    SendFile,
+    AcceptFile,
+    DeclineFile,
+    Bandwidth,
+    Seek,
+    Tracks,
+    Output,
+    Masks,
+    Zoom,
+    Depth,
+    Clip,
 }
 
 #[derive(Default)]
 pub struct Message {
     pub message_type: MessageType,
-    pub area: Area,
+    pub areas: Vec<Area>, // one for each track
     pub save_option: bool,
+    pub file_id: u32,
+    pub path: PathBuf,
+    pub bandwidth_cap: u64,
+    pub output_size: OutputSize,
+    pub position: Duration,
+    pub layout: TrackLayout,
+    pub record_mode: RecordMode,
+    pub masks: Vec<Mask>, // empty to cast without masks
+    pub zoom: Option<ZoomRequest>, // none to zoom out
+    pub depth: ColorDepth,
+    pub clip: Option<(Instant, Instant)>, // the frames received in between
+    pub gif_options: GifOptions,
 }
 
 impl Message {
@@ -41,12 +197,139 @@ impl Message {
             ..Default::default()
         }
     }
-    pub fn area_request(area: Area) -> Self {
+    pub fn area_request(areas: Vec<Area>) -> Self {
         Self {
             message_type: MessageType::Area,
-            area,
+            areas,
+            ..Default::default()
+        }
+    }
+    pub fn send_file_request(path: PathBuf) -> Self {
+        Self {
+            message_type: MessageType::SendFile,
+            path,
+            ..Default::default()
+        }
+    }
+    pub fn accept_file_request(file_id: u32, download_dir: PathBuf) -> Self {
+        Self {
+            message_type: MessageType::AcceptFile,
+            file_id,
+            path: download_dir,
+            ..Default::default()
+        }
+    }
+    pub fn decline_file_request(file_id: u32) -> Self {
+        Self {
+            message_type: MessageType::DeclineFile,
+            file_id,
+            ..Default::default()
+        }
+    }
+    pub fn bandwidth_request(bandwidth_cap: u64) -> Self {
+        Self {
+            message_type: MessageType::Bandwidth,
+            bandwidth_cap,
+            ..Default::default()
+        }
+    }
+    pub fn output_request(output_size: OutputSize) -> Self {
+        Self {
+            message_type: MessageType::Output,
+            output_size,
+            ..Default::default()
+        }
+    }
+    pub fn seek_request(position: Duration) -> Self {
+        Self {
+            message_type: MessageType::Seek,
+            position,
+            ..Default::default()
+        }
+    }
+    pub fn tracks_request(layout: TrackLayout, record_mode: RecordMode) -> Self {
+        Self {
+            message_type: MessageType::Tracks,
+            layout,
+            record_mode,
+            ..Default::default()
+        }
+    }
+    pub fn masks_request(masks: Vec<Mask>) -> Self {
+        Self {
+            message_type: MessageType::Masks,
+            masks,
+            ..Default::default()
+        }
+    }
+    pub fn zoom_request(zoom: Option<ZoomRequest>) -> Self {
+        Self {
+            message_type: MessageType::Zoom,
+            zoom,
+            ..Default::default()
+        }
+    }
+    pub fn depth_request(depth: ColorDepth) -> Self {
+        Self {
+            message_type: MessageType::Depth,
+            depth,
+            ..Default::default()
+        }
+    }
+    pub fn clip_request(from: Instant, to: Instant, gif_options: GifOptions, output: PathBuf) -> Self {
+        Self {
+            message_type: MessageType::Clip,
+            clip: Some((from, to)),
+            gif_options,
+            path: output,
             ..Default::default()
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn packets_are_read_back_without_padding() {
+        let mut stream = Vec::new();
+        let small = vec![7; 100];
+        let exact = vec![9; 2 * CHUNK_SIZE as usize];
+        let mut written = 0;
+        for payload in [&small, &exact] {
+            written += write_packet(&mut stream, &Header::control(PacketType::FileChunk, payload.len() as u32), payload).unwrap();
+        }
+        assert_eq!(written, stream.len());
+        // the small payload is padded to a whole chunk, the exact one is not padded at all
+        assert_eq!(stream.len(), 2 * header_size() + 3 * CHUNK_SIZE as usize);
+        let mut stream = Cursor::new(stream);
+        for payload in [small, exact] {
+            let (header, data) = read_packet(&mut stream).unwrap();
+            assert_eq!((header.packet_type, header.len as usize), (PacketType::FileChunk, payload.len()));
+            assert_eq!(data, payload);
+        }
+        assert!(read_packet(&mut stream).is_err());
+    }
+
+    #[test]
+    fn oversized_packets_are_rejected() {
+        for len in [MAX_PAYLOAD + 1, u32::MAX] {
+            let stream = bincode::serialize(&Header::control(PacketType::Frame, len)).unwrap();
+            let error = read_packet(&mut Cursor::new(stream)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn out_of_range_tracks_are_rejected() {
+        for track in [MAX_TRACKS, u32::MAX] {
+            let header = Header { track, ..Header::control(PacketType::Frame, 0) };
+            let error = read_packet(&mut Cursor::new(bincode::serialize(&header).unwrap())).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        let header = Header { track: MAX_TRACKS - 1, ..Header::control(PacketType::Frame, 0) };
+        assert_eq!(read_packet(&mut Cursor::new(bincode::serialize(&header).unwrap())).unwrap().0.track, MAX_TRACKS - 1);
+    }
+}
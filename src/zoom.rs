@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+use crate::capturer::Area;
+
+// time to zoom in or out
+const TRANSITION: Duration = Duration::from_millis(300);
+pub const MAX_FACTOR: f32 = 8.0;
+
+/// Where to zoom.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ZoomTarget {
+    /// Around the mouse pointer, where it is when zooming in.
+    #[default]
+    Pointer,
+    /// Around a point of the source.
+    Point(u32, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoomRequest {
+    pub factor: f32,
+    pub target: ZoomTarget,
+}
+
+/// Narrows the crop of a track around a point, and back to the whole area, with a smooth
+/// transition. The frames keep the size of the area, so that the receiver sees no difference.
+pub struct Zoom {
+    center: (u32, u32), // in source coordinates
+    factor: f32,
+    zoomed: bool,  // where the transition goes
+    progress: f32, // from 0, the whole area, to 1, fully zoomed
+    last: Instant,
+}
+
+impl Default for Zoom {
+    fn default() -> Self {
+        Self { center: (0, 0), factor: 1.0, zoomed: false, progress: 0.0, last: Instant::now() }
+    }
+}
+
+impl Zoom {
+    pub fn zoom_in(&mut self, center: (u32, u32), factor: f32) {
+        self.advance();
+        (self.center, self.factor, self.zoomed) = (center, factor.clamp(1.0, MAX_FACTOR), true);
+    }
+    pub fn zoom_out(&mut self) {
+        self.advance();
+        self.zoomed = false;
+    }
+    fn advance(&mut self) {
+        let step = self.last.elapsed().as_secs_f32() / TRANSITION.as_secs_f32();
+        self.last = Instant::now();
+        self.progress = if self.zoomed { (self.progress + step).min(1.0) } else { (self.progress - step).max(0.0) };
+    }
+    /// The part of `area` to crop now.
+    pub fn view(&mut self, area: &Area) -> Area {
+        self.advance();
+        if self.progress == 0.0 {
+            return area.clone();
+        }
+        // smoothstep, to start and stop gently
+        let t = self.progress * self.progress * (3.0 - 2.0 * self.progress);
+        let axis = |start: u32, size: u32, center: u32| {
+            let zoomed_size = size as f32 / self.factor;
+            let zoomed_start = (center as f32 - zoomed_size / 2.0).clamp(start as f32, (start + size) as f32 - zoomed_size);
+            let start = start as f32 + (zoomed_start - start as f32) * t;
+            let size = size as f32 + (zoomed_size - size as f32) * t;
+            (start.round() as u32, (size.round() as u32).max(1))
+        };
+        let (x, width) = axis(area.x, area.width, self.center.0);
+        let (y, height) = axis(area.y, area.height, self.center.1);
+        Area::new(x, y, width, height, area.selected_display)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settled(zoom: &mut Zoom) {
+        zoom.last -= TRANSITION;
+    }
+
+    #[test]
+    fn zoom_narrows_the_view_within_the_area() {
+        let area = Area::new(100, 100, 1280, 720, 0);
+        let mut zoom = Zoom::default();
+        assert_eq!(zoom.view(&area).width, 1280);
+        zoom.zoom_in((800, 500), 2.0);
+        settled(&mut zoom);
+        let view = zoom.view(&area);
+        assert_eq!((view.x, view.y, view.width, view.height), (480, 320, 640, 360));
+        // near the edge, the view stays inside the area
+        zoom.zoom_in((110, 110), 4.0);
+        let view = zoom.view(&area);
+        assert_eq!((view.x, view.y, view.width, view.height), (100, 100, 320, 180));
+    }
+
+    #[test]
+    fn zoom_goes_back_smoothly() {
+        let area = Area::new(0, 0, 1000, 1000, 0);
+        let mut zoom = Zoom::default();
+        zoom.zoom_in((500, 500), 2.0);
+        settled(&mut zoom);
+        assert_eq!(zoom.view(&area).width, 500);
+        zoom.zoom_out();
+        zoom.last -= TRANSITION / 2;
+        let halfway = zoom.view(&area).width;
+        assert!(halfway > 500 && halfway < 1000, "{halfway}");
+        settled(&mut zoom);
+        assert_eq!(zoom.view(&area).width, 1000);
+    }
+}